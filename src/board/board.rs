@@ -1,12 +1,11 @@
 use std::cmp::{max, min};
 use std::collections::HashMap;
 use fnv::FnvHashMap;
-use rand::Rng;
 use crate::board::condition::Condition;
 use crate::board::condition::Condition::{BLACK, NONE, RED};
 use crate::board::movee::Move;
 use crate::board::piece::Piece;
-use crate::engine::eval::Eval;
+use crate::engine::eval_params::EvalParams;
 
 #[derive(Clone)]
 pub struct Board {
@@ -27,6 +26,9 @@ pub struct Board {
     pub mg_score: [i32; 2],
     pub eg_score: [i32; 2],
 
+    // piece counts, [side][piece - 1]
+    material: [[u8; 7]; 2],
+
     // caches
     horizontal: Vec<(i8, i8)>,
     horse: Vec<(i8, i8)>,
@@ -36,6 +38,8 @@ pub struct Board {
     rng: Vec<Vec<Vec<u64>>>,
     rng_black: u64,
     hh: u64,
+    // hash of the soldiers, advisors and elephants only, for eval caches
+    structure_hh: u64,
 
     // cached move computation
     cache_moves: Vec<Move>,
@@ -55,6 +59,19 @@ impl Board {
     pub const ROWS: usize = 10;
     pub const COLS: usize = 9;
 
+    /// Bump when the zobrist keys change, saved hashes become invalid
+    pub const ZOBRIST_VERSION: u32 = 1;
+    const ZOBRIST_SEED: u64 = 0x5851_F42D_4C95_7F2D;
+
+    /// splitmix64, a fixed generator so keys do not depend on the rand crate
+    fn split_mix(state: &mut u64) -> u64 {
+        *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
     /// Creates a board
     pub fn new() -> Self {
         let board = vec![
@@ -92,39 +109,42 @@ impl Board {
         }
 
 
-        let mut rng = rand::thread_rng();
-        let rng_black = rng.random::<u64>();
+        // fixed seed so hashes are stable between runs, see ZOBRIST_VERSION
+        let mut seed = Self::ZOBRIST_SEED;
+        let rng_black = Self::split_mix(&mut seed);
         let mut rngs = vec![];
         for _ in 0..14 {
             let mut v1 = vec![];
             for _ in 0..10 {
                 let mut v2 = vec![];
                 for _ in 0..9 {
-                    v2.push(rng.random());
+                    v2.push(Self::split_mix(&mut seed));
                 }
                 v1.push(v2);
             }
             rngs.push(v1);
         }
 
-        let (mg, eg) = Eval::create_pst();
-        
-        
+        // tuned tables, load_params replaces them
+        let params = EvalParams::defaults();
+
         let mut item = Self {
             temp: false,
             state: board,
             player: Condition::RED,
             general: [9, 4, 0, 4],
-            eg_table: mg,
-            mg_table: eg,
+            eg_table: params.eg_pst_vec(),
+            mg_table: params.mg_pst_vec(),
             mg_score: [0, 0],
             eg_score: [0, 0],
+            material: [[2, 2, 2, 2, 1, 2, 5]; 2],
             horizontal,
             horse,
             diagonal,
             rng: rngs,
             rng_black,
             hh: 0,
+            structure_hh: 0,
             ply: 0,
             last_capture: 0,
             history: FnvHashMap::default(),
@@ -137,10 +157,72 @@ impl Board {
     }
 
     
+    /// Sets up a position from rows of `Piece::display` letters, black's back rank first
+    /// and `.` for empty squares
+    #[cfg(test)]
+    pub fn from_rows(rows: [&str; 10], player: Condition) -> Self {
+        let mut board = Self::new();
+        board.player = player;
+        board.mg_score = [0, 0];
+        board.eg_score = [0, 0];
+        board.material = [[0; 7]; 2];
+
+        for (row, text) in rows.iter().enumerate() {
+            let cells: Vec<char> = text.chars().collect();
+            assert_eq!(cells.len(), Self::COLS, "row {} should have {} squares", row, Self::COLS);
+
+            for (col, cell) in cells.into_iter().enumerate() {
+                let piece = if cell == '.' {
+                    Piece::SPACE
+                } else {
+                    let kind = (1..=7).find(|kind| Piece::display(*kind) == cell.to_ascii_uppercase().to_string())
+                        .unwrap_or_else(|| panic!("unknown piece {}", cell));
+                    if cell.is_ascii_uppercase() { kind } else { -kind }
+                };
+                board.state[row][col] = piece;
+                if piece == Piece::SPACE {
+                    continue;
+                }
+
+                let side = if piece > 0 { RED } else { BLACK };
+                let kind = piece.unsigned_abs() as usize - 1;
+                let coord = if side == BLACK { Move::flip_coord(&(row, col)) } else { (row, col) };
+                board.material[side as usize][kind] += 1;
+                board.mg_score[side as usize] += board.mg_table[kind][coord.0][coord.1];
+                board.eg_score[side as usize] += board.eg_table[kind][coord.0][coord.1];
+                if piece.abs() == Piece::GENERAL {
+                    board.general[2 * side as usize] = row as i8;
+                    board.general[2 * side as usize + 1] = col as i8;
+                }
+            }
+        }
+
+        board.hh = 0;
+        board.structure_hh = 0;
+        board.get_hash();
+        board
+    }
+
     pub fn load_pst(&mut self, mg: Vec<Vec<Vec<i32>>>, eg: Vec<Vec<Vec<i32>>>) {
         self.mg_table = mg;
         self.eg_table = eg;
     }
+
+    /// Loads the piece tables of a parameter file, call before any move is made
+    pub fn load_params(&mut self, params: &EvalParams) {
+        self.load_pst(params.mg_pst_vec(), params.eg_pst_vec());
+    }
+
+    /// Pieces strictly between two squares on the same row or column
+    pub fn pieces_between(&self, row: usize, col: usize, row2: usize, col2: usize) -> usize {
+        if row == row2 {
+            (col.min(col2) + 1..col.max(col2)).filter(|c| self.state[row][*c] != Piece::SPACE).count()
+        } else if col == col2 {
+            (row.min(row2) + 1..row.max(row2)).filter(|r| self.state[*r][col] != Piece::SPACE).count()
+        } else {
+            0
+        }
+    }
     
     /// Gets the hash for the specific cell
     fn get_hash_cell(&self, row: i8, col: i8) -> u64 {
@@ -159,12 +241,31 @@ impl Board {
         }
     }
 
+    /// Gets the hash for the cell if it holds a structure piece
+    fn get_structure_cell(&self, row: i8, col: i8) -> u64 {
+        match self.state[row as usize][col as usize].abs() {
+            Piece::SOLDIER | Piece::ADVISOR | Piece::ELEPHANT => self.get_hash_cell(row, col),
+            _ => 0,
+        }
+    }
+
+    /// Piece counts of a side indexed by piece - 1
+    pub fn material(&self, side: Condition) -> &[u8; 7] {
+        &self.material[side as usize]
+    }
+
+    /// Hash of the soldier, advisor and elephant placement, the same for either side to move
+    pub fn structure_hash(&self) -> u64 {
+        self.structure_hh
+    }
+
     /// Gets the board hash
     pub fn get_hash(&mut self) -> u64 {
         if self.hh == 0 {
             for row in 0..Self::ROWS {
                 for col in 0..Self::COLS {
                     self.hh ^= self.get_hash_cell(row as i8, col as i8);
+                    self.structure_hh ^= self.get_structure_cell(row as i8, col as i8);
                 }
             }
         }
@@ -189,7 +290,116 @@ impl Board {
             self.cache_ok = true;
             self.cache_moves = buffer.clone();
         }
-        
+
+        if captures {
+            buffer.retain(|mov| mov.captured != 0);
+        }
+
+        self.legal_moves(buffer)
+    }
+
+    /// Get the legal moves out of check: general moves, captures of the checkers and blocks.
+    /// Same as `get_moves` when not in check
+    pub fn get_evasions(&mut self) -> Vec<Move> {
+        let checkers = self.checkers();
+        if checkers.is_empty() {
+            return self.get_moves(false);
+        }
+
+        let grow = self.general[2*self.player as usize];
+        let gcol = self.general[2*self.player as usize + 1];
+
+        // squares that capture or block a checker, and own cannon screens that can step away
+        let mut targets = vec![];
+        let mut screens = vec![];
+        for (row, col) in checkers {
+            targets.push((row, col));
+
+            match self.state[row as usize][col as usize].abs() {
+                Piece::CHARIOT | Piece::CANNON => {
+                    let drow = (grow - row).signum();
+                    let dcol = (gcol - col).signum();
+                    let (mut r, mut c) = (row + drow, col + dcol);
+                    while (r, c) != (grow, gcol) {
+                        // empty squares block a chariot, or add a second cannon screen
+                        if self.state[r as usize][c as usize] == Piece::SPACE {
+                            targets.push((r, c));
+                        } else if self.get_cell_player(r, c) == self.player {
+                            screens.push((r, c));
+                        }
+                        r += drow;
+                        c += dcol;
+                    }
+                }
+                Piece::HORSE => {
+                    // the leg next to the horse, towards the general
+                    if (grow - row).abs() == 2 {
+                        targets.push((row + (grow - row) / 2, col));
+                    } else {
+                        targets.push((row, col + (gcol - col) / 2));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut buffer = vec![];
+        self.general_moves(grow, gcol, &mut buffer);
+
+        for row in 0..Self::ROWS as i8 {
+            for col in 0..Self::COLS as i8 {
+                if self.get_cell_player(row, col) != self.player || (row, col) == (grow, gcol) {
+                    continue;
+                }
+
+                let mut moves = vec![];
+                self.piece_moves(row, col, &mut moves);
+                if screens.contains(&(row, col)) {
+                    buffer.append(&mut moves);
+                } else {
+                    buffer.extend(moves.into_iter().filter(|mov| targets.contains(&(mov.endy, mov.endx))));
+                }
+            }
+        }
+
+        for mov in &mut buffer {
+            mov.captured = self.state[mov.endy as usize][mov.endx as usize];
+        }
+
+        self.legal_moves(buffer)
+    }
+
+    /// Get the legal quiet moves that check the other general
+    pub fn get_quiet_checks(&mut self) -> Vec<Move> {
+        let orow = self.general[2*self.player.inverse() as usize];
+        let ocol = self.general[2*self.player.inverse() as usize + 1];
+
+        // squares that can check the general, or block a line or horse leg towards it
+        let related = |row: i8, col: i8| {
+            let drow = (row - orow).abs();
+            let dcol = (col - ocol).abs();
+            drow == 0 || dcol == 0 || drow * dcol == 2 || (drow == 1 && dcol == 1)
+        };
+
+        let mut buffer = self.get_all_moves();
+        buffer.retain(|mov| mov.is_quiet() && (related(mov.starty, mov.startx) || related(mov.endy, mov.endx)));
+
+        let mut checks = vec![];
+        for mut mov in self.legal_moves(buffer) {
+            self.mov(&mut mov);
+            let check = self.is_check();
+            self.unmov(&mut mov);
+
+            if check {
+                checks.push(mov);
+            }
+        }
+
+        checks
+    }
+
+    /// Keeps the moves that do not leave the own general attacked
+    fn legal_moves(&mut self, mut buffer: Vec<Move>) -> Vec<Move> {
         // find own general and other
         let grow = self.general[2*self.player as usize];
         let gcol = self.general[2*self.player as usize + 1];
@@ -204,17 +414,6 @@ impl Board {
         let mut updated_buffer = vec![];
 
         for mov in buffer.iter_mut() {
-            if captures && mov.captured == 0 {
-                continue;
-            }
-            
-            // if !self.is_valid_move(&mov) {
-            //     println!("{}", self.display());
-            //     println!("Move {}", mov.display());
-            //     println!("oh no 2");
-            //     panic!("move is not legal in get_moves");
-            // }
-
             self.temp = true;
             self.mov(mov);
             if !self.will_check(&mov, &mut potential, grow, gcol, otherrow, othercol) {
@@ -247,21 +446,23 @@ impl Board {
 
     /// Checks if the current king is in check
     pub fn is_check(&mut self) -> bool {
+        !self.checkers().is_empty()
+    }
+
+    /// Squares of the opponent pieces giving check
+    pub fn checkers(&mut self) -> Vec<(i8, i8)> {
         // find own general
         let grow = self.general[2*self.player as usize];
         let gcol = self.general[2*self.player as usize + 1];
 
-        // find moves of other team
-        self.next_turn();
-        for mov in self.get_all_moves() {
-            if mov.endx == gcol && mov.endy == grow {
-                self.next_turn();
-                return true;
-            }
-        }
+        // look from the other team, flipped directly so the move cache stays valid
+        self.player = self.player.inverse();
+        let checkers = self.get_potentials(grow, gcol).into_iter()
+            .filter(|(row, col)| self.attacks(*row, *col, grow, gcol))
+            .collect();
+        self.player = self.player.inverse();
 
-        self.next_turn();
-        return false;
+        checkers
     }
 
 
@@ -288,6 +489,8 @@ impl Board {
         // remove hashes
         self.hh ^= self.get_hash_cell(mov.endy, mov.endx);
         self.hh ^= self.get_hash_cell(mov.starty, mov.startx);
+        self.structure_hh ^= self.get_structure_cell(mov.endy, mov.endx);
+        self.structure_hh ^= self.get_structure_cell(mov.starty, mov.startx);
 
         // handle last capture draws
         mov.last_capture = self.last_capture;
@@ -319,6 +522,7 @@ impl Board {
         let otherpiece = self.state[mov.endy as usize][mov.endx as usize].abs();
         if otherpiece != Piece::SPACE {
             let other = self.player.inverse() as usize;
+            self.material[other][otherpiece as usize - 1] -= 1;
             let otherend = Move::flip_coord(&end);
             self.mg_score[other] -= self.mg_table[otherpiece as usize - 1][otherend.0][otherend.1];
             self.eg_score[other] -= self.eg_table[otherpiece as usize - 1][otherend.0][otherend.1];
@@ -339,6 +543,7 @@ impl Board {
         self.state[mov.endy as usize][mov.endx as usize] = ch;
 
         self.hh ^= self.get_hash_cell(mov.endy, mov.endx);
+        self.structure_hh ^= self.get_structure_cell(mov.endy, mov.endx);
 
         self.next_turn();
 
@@ -372,6 +577,7 @@ impl Board {
 
         // remove hash at new position
         self.hh ^= self.get_hash_cell(mov.endy, mov.endx);
+        self.structure_hh ^= self.get_structure_cell(mov.endy, mov.endx);
 
         // handle last capture draw
         if mov.captured != Piece::SPACE {
@@ -396,6 +602,7 @@ impl Board {
         
         if mov.captured != Piece::SPACE {
             let otherpiece = mov.captured.abs();
+            self.material[self.player as usize][otherpiece as usize - 1] += 1;
             let otherend = Move::flip_coord(&end);
             self.mg_score[self.player as usize] += self.mg_table[otherpiece as usize - 1][otherend.0][otherend.1];
             self.eg_score[self.player as usize] += self.eg_table[otherpiece as usize - 1][otherend.0][otherend.1];
@@ -416,6 +623,8 @@ impl Board {
 
         self.hh ^= self.get_hash_cell(mov.starty, mov.startx);
         self.hh ^= self.get_hash_cell(mov.endy, mov.endx);
+        self.structure_hh ^= self.get_structure_cell(mov.starty, mov.startx);
+        self.structure_hh ^= self.get_structure_cell(mov.endy, mov.endx);
 
         self.next_turn();
         self.ply -= 1;
@@ -427,7 +636,8 @@ impl Board {
             return Condition::DRAW;
         }
 
-        let moves = self.get_moves(false);
+        // only evasions need to be tried when in check
+        let moves = self.get_evasions();
         if moves.len() == 0 {
             return self.player.inverse();
         }
@@ -452,9 +662,34 @@ impl Board {
         if self.exceeded {
             return true;
         }
+
+        if self.is_insufficient_material() {
+            return true;
+        }
         
         return false;
     }
+
+    /// Neither side can ever mate, whatever is played
+    pub fn is_insufficient_material(&self) -> bool {
+        self.cannot_win(RED) && self.cannot_win(BLACK)
+    }
+
+    /// Whether `side` can never mate: it has no piece that can cross the river, or only a cannon
+    /// against a bare general so there is nothing to jump over. Soldiers always count, even
+    /// unadvanced ones can still cross
+    pub fn cannot_win(&self, side: Condition) -> bool {
+        let own = &self.material[side as usize];
+        let other = &self.material[side.inverse() as usize];
+
+        let attackers = [Piece::CANNON, Piece::CHARIOT, Piece::HORSE, Piece::SOLDIER];
+        if attackers.iter().all(|piece| own[*piece as usize - 1] == 0) {
+            return true;
+        }
+
+        let pieces = |material: &[u8; 7]| material.iter().map(|count| *count as i32).sum::<i32>();
+        pieces(other) == 1 && pieces(own) == 2 && own[Piece::CANNON as usize - 1] == 1
+    }
     
     pub fn score_piece(&self, row: usize, col: usize) -> i32 {
         let piece = self.state[row][col];
@@ -773,6 +1008,35 @@ impl Board {
         return true;
     }
 
+    /// Adds the moves of the own piece on (row, col), maybe invalid for checks
+    fn piece_moves(&self, row: i8, col: i8, moves: &mut Vec<Move>) {
+        let sign = if self.player == RED { 1 } else { -1 };
+        match self.state[row as usize][col as usize] * sign {
+            Piece::SOLDIER => {
+                self.soldier_moves(row, col, moves);
+            }
+            Piece::CANNON => {
+                self.cannon_moves(row, col, moves, 0, 0);
+            }
+            Piece::CHARIOT => {
+                self.chariot_moves(row, col, moves, 0, 0);
+            }
+            Piece::ADVISOR => {
+                self.advisor_moves(row, col, moves);
+            }
+            Piece::ELEPHANT => {
+                self.elephant_moves(row, col, moves);
+            }
+            Piece::GENERAL => {
+                self.general_moves(row, col, moves);
+            }
+            Piece::HORSE => {
+                self.horse_moves(row, col, moves, 0, 0);
+            }
+            _ => {}
+        }
+    }
+
     /// Returns all possible (maybe invalid for checks) moves
     pub fn get_all_moves(&self) -> Vec<Move> {
         let mut mov_buffer = vec![];
@@ -784,32 +1048,8 @@ impl Board {
                     continue;
                 }
 
-                let irow = row as i8;
-                let icol = col as i8;
-
-                match self.state[row][col] * sign {
-                    Piece::SOLDIER => {
-                        self.soldier_moves(irow, icol, &mut mov_buffer);
-                    }
-                    Piece::CANNON => {
-                        self.cannon_moves(irow, icol, &mut mov_buffer, 0, 0);
-                    }
-                    Piece::CHARIOT => {
-                        self.chariot_moves(irow, icol, &mut mov_buffer, 0, 0);
-                    }
-                    Piece::ADVISOR => {
-                        self.advisor_moves(irow, icol, &mut mov_buffer);
-                    }
-                    Piece::ELEPHANT => {
-                        self.elephant_moves(irow, icol, &mut mov_buffer);
-                    }
-                    Piece::GENERAL => {
-                        self.general_moves(irow, icol, &mut mov_buffer);
-                    }
-                    Piece::HORSE => {
-                        self.horse_moves(irow, icol, &mut mov_buffer, 0, 0);
-                    }
-                    _ => {}
+                if self.state[row][col] * sign > 0 {
+                    self.piece_moves(row as i8, col as i8, &mut mov_buffer);
                 }
             }
         }
@@ -856,42 +1096,34 @@ impl Board {
             }
         }
 
-        let sign = if self.player == Condition::RED { 1 } else { -1 };
-
+        potential.iter().any(|(row, col)| self.attacks(*row, *col, grow, gcol))
+    }
 
-        for (row, col) in potential {
-            let row = *row;
-            let col = *col;
+    /// Checks if the piece of the current player on (row, col) attacks (grow, gcol)
+    fn attacks(&self, row: i8, col: i8, grow: i8, gcol: i8) -> bool {
+        if self.state[row as usize][col as usize] == Piece::SPACE {
+            return false;
+        }
 
-            if self.state[row as usize][col as usize] == Piece::SPACE {
-                continue;
+        let sign = if self.player == Condition::RED { 1 } else { -1 };
+        let mut check_buffer = vec![];
+        match sign * self.state[row as usize][col as usize] {
+            Piece::SOLDIER => {
+                self.soldier_moves(row, col, &mut check_buffer);
             }
-
-            let mut check_buffer = vec![];
-            match sign * self.state[row as usize][col as usize] {
-                Piece::SOLDIER => {
-                    self.soldier_moves(row, col, &mut check_buffer);
-                }
-                Piece::CANNON => {
-                    self.cannon_moves(row, col, &mut check_buffer, (gcol - col).signum(), (grow - row).signum());
-                }
-                Piece::CHARIOT => {
-                    self.chariot_moves(row, col, &mut check_buffer, (gcol - col).signum(), (grow - row).signum());
-                }
-                Piece::HORSE => {
-                    self.horse_moves(row, col, &mut check_buffer, gcol - col, grow - row);
-                }
-                _ => {}
+            Piece::CANNON => {
+                self.cannon_moves(row, col, &mut check_buffer, (gcol - col).signum(), (grow - row).signum());
             }
-
-            for mov in check_buffer {
-                if mov.endx == gcol && mov.endy == grow {
-                    return true;
-                }
+            Piece::CHARIOT => {
+                self.chariot_moves(row, col, &mut check_buffer, (gcol - col).signum(), (grow - row).signum());
             }
+            Piece::HORSE => {
+                self.horse_moves(row, col, &mut check_buffer, gcol - col, grow - row);
+            }
+            _ => {}
         }
 
-        return false;
+        check_buffer.iter().any(|mov| mov.endx == gcol && mov.endy == grow)
     }
 
     /// Return potential attacker squares
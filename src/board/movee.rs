@@ -65,6 +65,27 @@ impl Move {
         (self.endy * 9 + self.endx) as usize
     }
 
+    /// Packs the squares into 16 bits, 0 is the null move
+    pub fn pack(&self) -> u16 {
+        if self.is_null() {
+            return 0;
+        }
+
+        (((self.start_sq() << 7) | self.end_sq()) + 1) as u16
+    }
+
+    /// Unpacks a move from `pack`, the capture information is not restored
+    pub fn unpack(packed: u16) -> Move {
+        if packed == 0 {
+            return Move::null();
+        }
+
+        let packed = (packed - 1) as usize;
+        let start = packed >> 7;
+        let end = packed & 127;
+        Move::new((start / 9) as i8, (start % 9) as i8, (end / 9) as i8, (end % 9) as i8)
+    }
+
     pub fn display(&self) -> String {
         let cols: Vec<char> = "ABCDEFGHIJK".chars().collect();
         let rows: Vec<char> = "X987654321".chars().collect();
@@ -0,0 +1,142 @@
+use std::sync::OnceLock;
+use fnv::FnvHashMap;
+use crate::board::board::Board;
+use crate::board::condition::Condition;
+use crate::board::condition::Condition::{BLACK, RED};
+use crate::board::piece::Piece;
+
+// scale factors are out of this
+const ScaleOne: i32 = 64;
+
+// added for the stronger side in endgames known to be won
+const KnownWinBonus: i32 = 30;
+
+/// What is known about an endgame, from the stronger side's point of view
+#[derive(Clone, Copy)]
+enum Rule {
+    // multiply the eval by factor / ScaleOne
+    Scale(i32),
+    // replaces the eval, given the board, the stronger side and the eval from the side to move
+    Special(fn(&Board, Condition, i32) -> i32),
+}
+
+/// Material signature, 3 bits per piece kind except the general
+fn signature(material: &[u8; 7]) -> u32 {
+    material.iter()
+        .enumerate()
+        .filter(|(piece, _)| *piece as i8 != Piece::GENERAL - 1)
+        .fold(0, |key, (_, count)| key << 3 | (*count).min(7) as u32)
+}
+
+/// Piece counts from letters like "RAAEE", the general is implied
+fn parse(pieces: &str) -> [u8; 7] {
+    let mut material = [0u8; 7];
+    material[Piece::GENERAL as usize - 1] = 1;
+    for letter in pieces.chars() {
+        let piece = match letter {
+            'A' => Piece::ADVISOR,
+            'C' => Piece::CANNON,
+            'R' => Piece::CHARIOT,
+            'E' => Piece::ELEPHANT,
+            'H' => Piece::HORSE,
+            'S' => Piece::SOLDIER,
+            _ => panic!("unknown piece {} in endgame table", letter),
+        };
+        material[piece as usize - 1] += 1;
+    }
+    material
+}
+
+/// Every set of advisors and elephants a side can have
+const Defenders: [&str; 9] = ["", "A", "AA", "E", "EE", "AE", "AAE", "AEE", "AAEE"];
+
+/// Rules keyed by the stronger side's signature, then the weaker side's
+fn table() -> &'static FnvHashMap<(u32, u32), Rule> {
+    static Table: OnceLock<FnvHashMap<(u32, u32), Rule>> = OnceLock::new();
+    Table.get_or_init(|| {
+        let mut table = FnvHashMap::default();
+        let mut add = |strong: &str, weak: &str, rule: Rule| {
+            table.insert((signature(&parse(strong)), signature(&parse(weak))), rule);
+        };
+
+        // a horse or cannon cannot break full defenders
+        add("H", "AAEE", Rule::Scale(4));
+        add("C", "AAEE", Rule::Scale(4));
+
+        for defenders in Defenders {
+            // a lone soldier depends on how far it got
+            add("S", defenders, Rule::Special(lone_soldier));
+            // a chariot against a chariot is drawn whatever defends
+            add("R", &format!("R{}", defenders), Rule::Scale(4));
+            // a chariot wins against defenders alone
+            add("R", defenders, Rule::Special(known_win));
+        }
+
+        table
+    })
+}
+
+/// Eval from the side to move, given the stronger side's score
+fn from_strong(board: &Board, strong: Condition, score: i32) -> i32 {
+    if board.player == strong { score } else { -score }
+}
+
+fn known_win(board: &Board, strong: Condition, eval: i32) -> i32 {
+    eval + from_strong(board, strong, KnownWinBonus)
+}
+
+/// Soldier rows counted from the weak side's back rank
+fn soldier_depth(board: &Board, strong: Condition) -> Option<usize> {
+    let soldier = if strong == RED { Piece::SOLDIER } else { -Piece::SOLDIER };
+    for row in 0..Board::ROWS {
+        for col in 0..Board::COLS {
+            if board.state[row][col] == soldier {
+                return Some(if strong == RED { row } else { Board::ROWS - 1 - row });
+            }
+        }
+    }
+    None
+}
+
+fn lone_soldier(board: &Board, strong: Condition, eval: i32) -> i32 {
+    let Some(depth) = soldier_depth(board, strong) else {
+        return eval;
+    };
+    let weak = strong.inverse();
+    let defenders = board.material(weak)[Piece::ADVISOR as usize - 1] + board.material(weak)[Piece::ELEPHANT as usize - 1];
+
+    // a soldier on the back rank can only move sideways and never mates
+    if depth == 0 {
+        return 0;
+    }
+
+    match defenders {
+        0 => known_win(board, strong, eval),
+        // a soldier still above the palace can win against one defender
+        1 if depth >= 3 => eval / 2,
+        _ => eval * 8 / ScaleOne,
+    }
+}
+
+/// Adjusts an eval from the side to move with endgame knowledge, dead draws never get here
+/// since `Board::is_draw` already covers them
+pub fn adjust(board: &Board, eval: i32) -> i32 {
+    let red = signature(board.material(RED));
+    let black = signature(board.material(BLACK));
+    let rule = table().get(&(red, black)).map(|rule| (RED, *rule))
+        .or_else(|| table().get(&(black, red)).map(|rule| (BLACK, *rule)));
+
+    let eval = match rule {
+        Some((_, Rule::Scale(factor))) => eval * factor / ScaleOne,
+        Some((strong, Rule::Special(special))) => special(board, strong, eval),
+        None => eval,
+    };
+
+    // the side ahead cannot convert without attackers
+    let ahead = if eval > 0 { board.player } else { board.player.inverse() };
+    if eval != 0 && board.cannot_win(ahead) {
+        return eval / 16;
+    }
+
+    eval
+}
@@ -1,13 +1,87 @@
 use std::fmt::format;
 use std::fs;
 use crate::board::board::Board;
+use crate::board::condition::Condition;
 use crate::board::condition::Condition::{BLACK, RED};
 use crate::board::piece::Piece;
+use serde::Serialize;
+use crate::engine::endgame;
+use crate::engine::eval_params::{EvalParams, KingSafety, Structure};
+
+/// One evaluation term for each side, indexed by RED and BLACK
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct TraceTerm {
+    pub mg: [i32; 2],
+    pub eg: [i32; 2],
+}
+
+/// Breakdown of a static evaluation, see `Eval::evaluate_trace`
+#[derive(Default, Serialize)]
+pub struct EvalTrace {
+    // side the score is from
+    pub player: String,
+    pub pst: TraceTerm,
+    // indexed by piece - 1, only cannons, chariots, horses and soldiers have one
+    pub mobility: [TraceTerm; 7],
+    // minus the danger to each general
+    pub king_safety: TraceTerm,
+    pub structure: TraceTerm,
+    pub tempo: TraceTerm,
+    // 0 at the start, 256 with every non-soldier piece traded
+    pub phase: i32,
+    // side to move minus the other side, before tapering
+    pub mg: i32,
+    pub eg: i32,
+    pub tapered: i32,
+    // after endgame scaling
+    pub score: i32,
+}
+
+impl EvalTrace {
+    pub fn display(&self) -> String {
+        let title = ["Advisor", "Cannon", "Chariot", "Elephant", "General", "Horse", "Soldier"];
+        let row = |name: &str, term: &TraceTerm| {
+            format!("{: <20}{: >8}{: >8}{: >8}{: >8}\n", name, term.mg[0], term.mg[1], term.eg[0], term.eg[1])
+        };
+
+        let mut output = format!("{: <20}{: >8}{: >8}{: >8}{: >8}\n", "Term", "Red MG", "Blk MG", "Red EG", "Blk EG");
+        output += &row("PST material", &self.pst);
+        for (piece, term) in self.mobility.iter().enumerate() {
+            if term.mg != [0, 0] || term.eg != [0, 0] {
+                output += &row(&format!("{} mobility", title[piece]), term);
+            }
+        }
+        output += &row("King safety", &self.king_safety);
+        output += &row("Structure", &self.structure);
+        output += &row("Tempo", &self.tempo);
+        output += &format!("Phase {}/256, MG {}, EG {}, Tapered {}, Score {} for {}\n",
+                           self.phase, self.mg, self.eg, self.tapered, self.score, self.player);
+        output
+    }
+}
+
+/// Soldier, advisor and elephant counts of both sides, only depend on the structure hash
+#[derive(Clone, Copy, Default)]
+struct StructureEntry {
+    key: u64,
+    // [side][connected elephants, connected advisors, linked soldiers, crossed soldiers]
+    counts: [[i32; 4]; 2],
+    // bit per file holding a soldier of either side
+    soldier_files: u16,
+}
 
 pub struct Eval {
     pub tempo_score: i32,
     pub mobility_mg: [i32; 7],
     pub mobility_eg: [i32; 7],
+    // indexed by piece - 1
+    pub phase: [i32; 7],
+    pub king_safety: KingSafety,
+    pub structure: Structure,
+    // params the eval and the board tables were made from, 0 for `new`
+    pub fingerprint: u64,
+
+    structure_cache: Vec<StructureEntry>,
 }
 
 impl Eval {
@@ -18,7 +92,9 @@ impl Eval {
     const CannonPhase: i32 = 3;
     const ElephantPhase: i32 = 1;
     const AdvisorPhase: i32 = 1;
-    const TotalPhase: i32 = Self::SoliderPhase * 10 + Self::HorsePhase * 4 + Self::ChariotPhase * 4 + Self::ElephantPhase * 4 + Self::AdvisorPhase * 4 + Self::CannonPhase * 4;
+    const PhaseWeights: [i32; 7] = [Self::AdvisorPhase, Self::CannonPhase, Self::ChariotPhase, Self::ElephantPhase, 0, Self::HorsePhase, Self::SoliderPhase];
+
+    const StructureCacheSize: usize = 1 << 14;
 
     // base values, unused
     const BasePieceScore: [i32; 7] = [20, 50, 100, 20, 10, 35, 10];
@@ -29,119 +105,404 @@ impl Eval {
             tempo_score: 0,
             mobility_mg: [0;7],
             mobility_eg: [0;7],
+            phase: Self::PhaseWeights,
+            king_safety: KingSafety::default(),
+            structure: Structure::default(),
+            fingerprint: 0,
+            structure_cache: vec![StructureEntry::default(); Self::StructureCacheSize],
         }
     }
 
-    pub fn evaluate(&self, board: &mut Board) -> i32 {
+    /// Tempo, mobility and phase weights from a parameter file, the tables go to `Board::load_params`
+    pub fn from_params(params: &EvalParams) -> Self {
+        Self {
+            tempo_score: params.tempo,
+            mobility_mg: params.mobility_mg,
+            mobility_eg: params.mobility_eg,
+            phase: params.phase,
+            king_safety: params.king_safety,
+            structure: params.structure,
+            fingerprint: params.fingerprint(),
+            structure_cache: vec![StructureEntry::default(); Self::StructureCacheSize],
+        }
+    }
+
+    pub fn evaluate(&mut self, board: &mut Board) -> i32 {
         let mut mg_eval = board.mg_score[board.player as usize] - board.mg_score[board.player.inverse() as usize];
         let mut eg_eval = board.eg_score[board.player as usize] - board.eg_score[board.player.inverse() as usize];
         
         // also evaluate mobility
         for row in 0..10 {
             for col in 0..9 {
-                if board.state[row][col] == Piece::SPACE {
+                let piece = board.state[row][col];
+                let Some(count) = Self::mobility(board, row, col) else {
                     continue;
-                }
-                
-                let mut piece = board.state[row][col];
-                let mut sign = if piece > 0 && board.player == RED || piece < 0 && board.player == BLACK { 1 } else {-1};
-                if sign != 1 {
-                    board.player = board.player.inverse();
-                }                
-                
-                let row = row as i8;
-                let col = col as i8;
-                let piece = piece.abs();
-                let mut moves = vec![];
-                match piece {
-                    Piece::SOLDIER => {
-                        board.soldier_moves(row, col, &mut moves);
-                        mg_eval += sign*self.mobility_mg[(piece-1) as usize] * (moves.len()) as i32;
-                        eg_eval += sign*self.mobility_eg[(piece-1) as usize] * (moves.len()) as i32;
-                    },
-                    Piece::CANNON => {
-                        board.cannon_moves(row, col, &mut moves, 0, 0);
-                        mg_eval += sign*self.mobility_mg[(piece-1) as usize] * (moves.len()  as i32 -7);
-                        eg_eval += sign*self.mobility_eg[(piece-1) as usize] * (moves.len()  as i32 -7);
-                    },
-                    Piece::CHARIOT => {
-                        board.chariot_moves(row, col, &mut moves, 0, 0);
-                        mg_eval += sign*self.mobility_mg[(piece-1) as usize] * (moves.len()  as i32-7);
-                        eg_eval += sign*self.mobility_eg[(piece-1) as usize] * (moves.len()  as i32 -7);
-                    },
-                    Piece::HORSE => {
-                        board.horse_moves(row, col, &mut moves, 0,0 );
-                        mg_eval += sign*self.mobility_mg[(piece-1) as usize] * (moves.len() as i32-2);
-                        eg_eval += sign*self.mobility_eg[(piece-1) as usize] * (moves.len() as i32-2);
-                    },
-                    _ => {}
-                }
-                
-                if sign != 1 {
-                    board.player = board.player.inverse();
-                }
+                };
+
+                let sign = if piece > 0 && board.player == RED || piece < 0 && board.player == BLACK { 1 } else {-1};
+                let kind = (piece.abs() - 1) as usize;
+                mg_eval += sign*self.mobility_mg[kind] * count;
+                eg_eval += sign*self.mobility_eg[kind] * count;
             }
         }
         
+        // general safety, the danger to each side is a bonus for the other
+        let (us, them) = (board.player as usize, board.player.inverse() as usize);
+        let danger = self.king_danger(board);
+        mg_eval += danger[them][0] - danger[us][0];
+        eg_eval += danger[them][1] - danger[us][1];
+
+        let counts = self.cached_structure(board);
+        let structure = self.structure_score(board, &counts);
+        mg_eval += structure[us][0] - structure[them][0];
+        eg_eval += structure[us][1] - structure[them][1];
+
         // add tempo
         mg_eval += self.tempo_score;
         
         // linear interpolate between mg and eg eval
         // https://www.chessprogramming.org/Tapered_Eval
-        let phase = Self::compute_phase(board);
-        return ((mg_eval * (256 - phase)) + (eg_eval * phase)) / 256;
+        let phase = self.compute_phase(board);
+        let tapered = ((mg_eval * (256 - phase)) + (eg_eval * phase)) / 256;
+        endgame::adjust(board, tapered)
     }
 
+    /// Same score as `evaluate`, with every term reported for each side
+    pub fn evaluate_trace(&self, board: &mut Board) -> EvalTrace {
+        let mut trace = EvalTrace::default();
+        for side in [RED, BLACK] {
+            trace.pst.mg[side as usize] = board.mg_score[side as usize];
+            trace.pst.eg[side as usize] = board.eg_score[side as usize];
+        }
 
-    fn compute_phase(board: &mut Board) -> i32 {
-        // https://www.chessprogramming.org/Tapered_Eval
-        let mut phase = Self::TotalPhase;
-        let lookup = [0, Self::AdvisorPhase, Self::CannonPhase, Self::ChariotPhase, Self::ElephantPhase, 0, Self::HorsePhase, Self::SoliderPhase];
-        for row in board.state.iter() {
-            for cell in row.iter() {
-                let piece = cell.abs() as usize;
-                phase -= lookup[piece];
+        for row in 0..10 {
+            for col in 0..9 {
+                let piece = board.state[row][col];
+                let Some(count) = Self::mobility(board, row, col) else {
+                    continue;
+                };
+
+                let side = if piece > 0 { RED } else { BLACK } as usize;
+                let kind = (piece.abs() - 1) as usize;
+                trace.mobility[kind].mg[side] += self.mobility_mg[kind] * count;
+                trace.mobility[kind].eg[side] += self.mobility_eg[kind] * count;
             }
         }
 
-        return (phase * 256 + (Self::TotalPhase / 2)) / Self::TotalPhase;
+        let danger = self.king_danger(board);
+        for side in [RED, BLACK] {
+            trace.king_safety.mg[side as usize] = -danger[side as usize][0];
+            trace.king_safety.eg[side as usize] = -danger[side as usize][1];
+        }
+
+        let structure = self.structure_score(board, &Self::structure_counts(board));
+        for side in [RED, BLACK] {
+            trace.structure.mg[side as usize] = structure[side as usize][0];
+            trace.structure.eg[side as usize] = structure[side as usize][1];
+        }
+
+        trace.tempo.mg[board.player as usize] = self.tempo_score;
+
+        let us = board.player as usize;
+        let them = board.player.inverse() as usize;
+        let terms = std::iter::once(&trace.pst).chain(trace.mobility.iter())
+            .chain([&trace.king_safety, &trace.structure, &trace.tempo]);
+        for term in terms {
+            trace.mg += term.mg[us] - term.mg[them];
+            trace.eg += term.eg[us] - term.eg[them];
+        }
+
+        trace.player = board.player.display();
+        trace.phase = self.compute_phase(board);
+        trace.tapered = ((trace.mg * (256 - trace.phase)) + (trace.eg * trace.phase)) / 256;
+        trace.score = endgame::adjust(board, trace.tapered);
+        trace
     }
-}
 
-impl Eval {
-    pub fn load_pst(file: &str) -> (Vec<Vec<Vec<i32>>>, Vec<Vec<Vec<i32>>>) {
-        let text = fs::read_to_string(file).unwrap();
-        
-        let rows: Vec<&str> = text.split("\n").collect();
-        
-        let (mut mg_pst, mut eg_pst) = Eval::create_pst();
-        
-        let pieces = 7;
-        
-        // 1..11 for mg
-        // 14..24 for eg
-        for row in 1..11 {
-            let values: Vec<&str> = rows[row].split(",").collect();
-            for piece in 0..pieces {
-                for col in 0..9 {
-                    mg_pst[piece][row-1][col] = values[9*piece + col].trim().parse::<i32>().unwrap();
+    /// Danger to each side's general as [side][mg, eg], higher is worse, from one pass over the board
+    fn king_danger(&self, board: &Board) -> [[i32; 2]; 2] {
+        let weights = &self.king_safety;
+
+        let mut danger = [[0, 0], [0, 0]];
+        if !weights.has_weights() {
+            return danger;
+        }
+
+        let mut add = |side: usize, weight: [i32; 2], count: i32| {
+            danger[side][0] += weight[0] * count;
+            danger[side][1] += weight[1] * count;
+        };
+
+        // defenders of each side, and the pieces aimed at each side's general
+        let mut advisors = [0; 2];
+        let mut elephants = [0; 2];
+        let mut attackers = [0; 2];
+        let mut central_cannon = [false; 2];
+        let mut chariot_on_file = [false; 2];
+        for row in 0..Board::ROWS {
+            for col in 0..Board::COLS {
+                let piece = board.state[row][col];
+                if piece == Piece::SPACE {
+                    continue;
+                }
+
+                let owner = if piece > 0 { RED } else { BLACK };
+                match piece.abs() {
+                    Piece::ADVISOR => advisors[owner as usize] += 1,
+                    Piece::ELEPHANT => elephants[owner as usize] += 1,
+                    _ => {}
+                }
+
+                // the other pieces threaten the other general
+                let side = owner.inverse();
+                let target = side as usize;
+                let grow = board.general[2 * target] as usize;
+                let gcol = board.general[2 * target + 1] as usize;
+                // rows counted from the defender's back rank
+                let rrow = if side == RED { 9 - row } else { row };
+
+                match piece.abs() {
+                    Piece::CHARIOT | Piece::HORSE | Piece::CANNON | Piece::SOLDIER => {
+                        if rrow <= 3 && (2..=6).contains(&col) {
+                            add(target, weights.palace_attacker, 1);
+                        }
+                    }
+                    _ => {}
+                }
+
+                match piece.abs() {
+                    Piece::CHARIOT => {
+                        attackers[target] += 1;
+                        if (row == grow || col == gcol) && board.pieces_between(row, col, grow, gcol) == 0 {
+                            add(target, weights.open_chariot, 1);
+                            chariot_on_file[target] |= col == gcol;
+                        }
+                    }
+                    Piece::HORSE => {
+                        attackers[target] += 1;
+                        if rrow == 1 && (col == 2 || col == 6) {
+                            add(target, weights.wo_cao_horse, 1);
+                        }
+                        if (rrow == 0 || rrow == 2) && (col == 3 || col == 5) {
+                            add(target, weights.gua_jiao_horse, 1);
+                        }
+                    }
+                    Piece::CANNON => {
+                        attackers[target] += 1;
+                        if col == gcol {
+                            match board.pieces_between(row, col, grow, gcol) {
+                                0 => add(target, weights.hollow_cannon, 1),
+                                1 | 2 => add(target, weights.screened_cannon, 1),
+                                _ => {}
+                            }
+                        }
+                        // covers the center of the general's rank
+                        if col == 4 && gcol != 4 && board.pieces_between(row, col, grow, 4) <= 1 {
+                            central_cannon[target] = true;
+                        }
+                    }
+                    _ => {}
                 }
-            }    
+            }
         }
 
-        for row in 14..24 {
-            let values: Vec<&str> = rows[row].split(",").collect();
-            for piece in 0..pieces {
-                for col in 0..9 {
-                    eg_pst[piece][row-14][col] = values[9*piece + col].trim().parse::<i32>().unwrap();
+        for side in 0..2 {
+            add(side, weights.missing_advisor, (2 - advisors[side]) * attackers[side]);
+            add(side, weights.missing_elephant, (2 - elephants[side]) * attackers[side]);
+            if central_cannon[side] && chariot_on_file[side] {
+                add(side, weights.iron_bolt, 1);
+            }
+        }
+
+        danger
+    }
+
+    /// Structure counts from the cache, computed on a miss
+    fn cached_structure(&mut self, board: &Board) -> StructureEntry {
+        let key = board.structure_hash();
+        let index = key as usize & (Self::StructureCacheSize - 1);
+        if self.structure_cache[index].key != key {
+            self.structure_cache[index] = Self::structure_counts(board);
+        }
+        self.structure_cache[index]
+    }
+
+    fn structure_counts(board: &Board) -> StructureEntry {
+        let mut entry = StructureEntry { key: board.structure_hash(), ..Default::default() };
+
+        // [side] -> squares
+        let mut elephants = [vec![], vec![]];
+        let mut advisors = [vec![], vec![]];
+        let mut crossed = [vec![], vec![]];
+        for row in 0..Board::ROWS {
+            for col in 0..Board::COLS {
+                let piece = board.state[row][col];
+                let side = if piece > 0 { RED } else { BLACK } as usize;
+                match piece.abs() {
+                    Piece::ELEPHANT => elephants[side].push((row as i32, col as i32)),
+                    Piece::ADVISOR => advisors[side].push((row as i32, col as i32)),
+                    Piece::SOLDIER => {
+                        entry.soldier_files |= 1 << col;
+                        if (side == RED as usize && row <= 4) || (side == BLACK as usize && row >= 5) {
+                            crossed[side].push((row as i32, col as i32));
+                        }
+                    }
+                    _ => {}
                 }
             }
         }
-        
 
-        (mg_pst, eg_pst)
+        for side in 0..2 {
+            let counts = &mut entry.counts[side];
+            if let [a, b] = elephants[side][..] {
+                counts[0] = ((a.0 - b.0).abs() == 2 && (a.1 - b.1).abs() == 2) as i32;
+            }
+            if let [a, b] = advisors[side][..] {
+                counts[1] = ((a.0 - b.0).abs() == 1 && (a.1 - b.1).abs() == 1) as i32;
+            }
+            for (index, a) in crossed[side].iter().enumerate() {
+                counts[2] += crossed[side][index + 1..].iter()
+                    .filter(|b| a.0 == b.0 && (a.1 - b.1).abs() == 1)
+                    .count() as i32;
+            }
+            counts[3] = crossed[side].len() as i32;
+        }
+
+        entry
     }
-    
+
+    /// Structure and coordination bonus of each side as [side][mg, eg]
+    fn structure_score(&self, board: &Board, entry: &StructureEntry) -> [[i32; 2]; 2] {
+        let weights = &self.structure;
+        let mut score = [[0, 0], [0, 0]];
+        let mut add = |side: usize, weight: [i32; 2], count: i32| {
+            score[side][0] += weight[0] * count;
+            score[side][1] += weight[1] * count;
+        };
+
+        for side in 0..2 {
+            let counts = entry.counts[side];
+            add(side, weights.connected_elephants, counts[0]);
+            add(side, weights.connected_advisors, counts[1]);
+            add(side, weights.linked_soldiers, counts[2]);
+            add(side, weights.crossed_soldier, counts[3]);
+        }
+
+        // the remaining terms scan the whole board, skipped while their weights are zero
+        if !weights.has_board_terms() {
+            return score;
+        }
+
+        let in_board = |row: i32, col: i32| (0..Board::ROWS as i32).contains(&row) && (0..Board::COLS as i32).contains(&col);
+        let occupied = |row: i32, col: i32| in_board(row, col) && board.state[row as usize][col as usize] != Piece::SPACE;
+
+        // file of the first cannon seen per side
+        let mut cannon_file = [None, None];
+        for row in 0..Board::ROWS {
+            for col in 0..Board::COLS {
+                let piece = board.state[row][col];
+                let side = if piece > 0 { RED } else { BLACK } as usize;
+                let (row, col) = (row as i32, col as i32);
+                match piece.abs() {
+                    Piece::HORSE => {
+                        let legs = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter()
+                            .filter(|(dr, dc)| occupied(row + dr, col + dc))
+                            .count();
+                        add(side, weights.blocked_horse_leg, legs as i32);
+                    }
+                    Piece::CHARIOT => {
+                        if entry.soldier_files & (1 << col) == 0 {
+                            add(side, weights.open_file_chariot, 1);
+                        }
+                    }
+                    Piece::CANNON => {
+                        if cannon_file[side] == Some(col) {
+                            add(side, weights.doubled_cannons, 1);
+                        }
+                        cannon_file[side] = Some(col);
+
+                        // a screen with any piece behind it in some direction
+                        let screened = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().any(|(dr, dc)| {
+                            let (mut r, mut c) = (row + dr, col + dc);
+                            let mut pieces = 0;
+                            while in_board(r, c) {
+                                if occupied(r, c) {
+                                    pieces += 1;
+                                    if pieces == 2 {
+                                        return true;
+                                    }
+                                }
+                                r += dr;
+                                c += dc;
+                            }
+                            false
+                        });
+                        if !screened {
+                            add(side, weights.unscreened_cannon, 1);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        score
+    }
+
+    /// Moves of the piece relative to a typical count, None for pieces without a mobility term
+    fn mobility(board: &mut Board, row: usize, col: usize) -> Option<i32> {
+        let piece = board.state[row][col];
+        if piece == Piece::SPACE {
+            return None;
+        }
+
+        // moves are generated for the side to move
+        let player = board.player;
+        board.player = if piece > 0 { RED } else { BLACK };
+
+        let row = row as i8;
+        let col = col as i8;
+        let mut moves = vec![];
+        let count = match piece.abs() {
+            Piece::SOLDIER => {
+                board.soldier_moves(row, col, &mut moves);
+                Some(moves.len() as i32)
+            },
+            Piece::CANNON => {
+                board.cannon_moves(row, col, &mut moves, 0, 0);
+                Some(moves.len() as i32 - 7)
+            },
+            Piece::CHARIOT => {
+                board.chariot_moves(row, col, &mut moves, 0, 0);
+                Some(moves.len() as i32 - 7)
+            },
+            Piece::HORSE => {
+                board.horse_moves(row, col, &mut moves, 0, 0);
+                Some(moves.len() as i32 - 2)
+            },
+            _ => None
+        };
+
+        board.player = player;
+        count
+    }
+
+
+    fn compute_phase(&self, board: &Board) -> i32 {
+        // https://www.chessprogramming.org/Tapered_Eval
+        let total = EvalParams::total_phase(&self.phase);
+        let mut phase = total;
+        for side in [RED, BLACK] {
+            for (kind, count) in board.material(side).iter().enumerate() {
+                phase -= self.phase[kind] * *count as i32;
+            }
+        }
+
+        return (phase * 256 + (total / 2)) / total;
+    }
+}
+
+impl Eval {
     pub fn create_pst() -> (Vec<Vec<Vec<i32>>>, Vec<Vec<Vec<i32>>>) {
         // 7 pieces, 10 by 9 board, [piece, row, col]
         let mut mgpst = vec![vec![vec![0; 9]; 10]; 7];
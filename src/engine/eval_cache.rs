@@ -0,0 +1,42 @@
+/// A static evaluation and the full hash of its position, a zero key is an empty entry
+#[derive(Clone, Copy, Default)]
+struct EvalEntry {
+    key: u64,
+    score: i32,
+}
+
+/// Static evaluations by zobrist hash, one entry per index, newer evaluations replace older ones
+pub struct EvalCache {
+    entries: Vec<EvalEntry>,
+}
+
+impl EvalCache {
+    /// Creates a cache of `size` entries, a power of two
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "eval cache size should be a power of two");
+        Self { entries: vec![EvalEntry::default(); size] }
+    }
+
+    /// Empties every entry, needed when the evaluator changes
+    pub fn clear(&mut self) {
+        self.entries.fill(EvalEntry::default());
+    }
+
+    pub fn probe(&self, hash: u64) -> Option<i32> {
+        let entry = &self.entries[self.index(hash)];
+        if entry.key == hash && hash != 0 {
+            Some(entry.score)
+        } else {
+            None
+        }
+    }
+
+    pub fn store(&mut self, hash: u64, score: i32) {
+        let index = self.index(hash);
+        self.entries[index] = EvalEntry { key: hash, score };
+    }
+
+    fn index(&self, hash: u64) -> usize {
+        hash as usize & (self.entries.len() - 1)
+    }
+}
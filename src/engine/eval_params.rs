@@ -0,0 +1,308 @@
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::OnceLock;
+use fnv::FnvHasher;
+use serde::{Deserialize, Serialize};
+
+/// Piece tables indexed [piece - 1][row][col], from red's side of the board
+pub type Pst = [[[i32; 9]; 10]; 7];
+
+/// Everything the handcrafted evaluation is tuned on, stored as json.
+/// Per piece arrays are ordered advisor, cannon, chariot, elephant, general, horse, soldier
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EvalParams {
+    pub version: u32,
+    pub tempo: i32,
+    pub mobility_mg: [i32; 7],
+    pub mobility_eg: [i32; 7],
+    // how much each piece moves the game towards the middlegame
+    pub phase: [i32; 7],
+    pub mg_pst: Pst,
+    pub eg_pst: Pst,
+    // version 1 files have no king safety terms, they default to zero
+    #[serde(default)]
+    pub king_safety: KingSafety,
+    // added in version 3
+    #[serde(default)]
+    pub structure: Structure,
+}
+
+/// Penalties for a general under attack, each as [mg, eg], see `Eval::king_danger`.
+/// The first values were picked by hand and never measured, the embedded params ship them at zero
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct KingSafety {
+    // per missing advisor or elephant, per enemy chariot, horse and cannon
+    pub missing_advisor: [i32; 2],
+    pub missing_elephant: [i32; 2],
+    // per enemy piece in or just outside the palace
+    pub palace_attacker: [i32; 2],
+    // cannon on the general's file behind one or two pieces
+    pub screened_cannon: [i32; 2],
+    // cannon on the general's file with nothing between
+    pub hollow_cannon: [i32; 2],
+    // horse two files off center on the second rank, 卧槽马
+    pub wo_cao_horse: [i32; 2],
+    // horse on a palace corner, 挂角马
+    pub gua_jiao_horse: [i32; 2],
+    // chariot on the general's file or rank with nothing between
+    pub open_chariot: [i32; 2],
+    // central cannon keeping the general off the center while a chariot holds its file, 铁门栓
+    pub iron_bolt: [i32; 2],
+}
+
+/// Piece coordination and structure bonuses, each as [mg, eg], negative for weaknesses.
+/// Not tuned yet, so the embedded params leave every weight at zero
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Structure {
+    // per occupied leg square around a horse
+    pub blocked_horse_leg: [i32; 2],
+    // elephants or advisors guarding each other
+    pub connected_elephants: [i32; 2],
+    pub connected_advisors: [i32; 2],
+    // per pair of crossed soldiers side by side
+    pub linked_soldiers: [i32; 2],
+    pub crossed_soldier: [i32; 2],
+    // cannon with nothing to jump over to any piece
+    pub unscreened_cannon: [i32; 2],
+    // chariot on a file without soldiers
+    pub open_file_chariot: [i32; 2],
+    // both cannons on one file
+    pub doubled_cannons: [i32; 2],
+}
+
+impl Structure {
+    /// Whether any term that needs a full board scan is weighted, the rest only depend on the structure hash
+    pub fn has_board_terms(&self) -> bool {
+        [self.blocked_horse_leg, self.unscreened_cannon, self.open_file_chariot, self.doubled_cannons]
+            .iter()
+            .any(|weight| *weight != [0, 0])
+    }
+
+    /// Weights in file order, with their json names
+    pub fn named(&self) -> [(&'static str, [i32; 2]); 8] {
+        [
+            ("blocked_horse_leg", self.blocked_horse_leg),
+            ("connected_elephants", self.connected_elephants),
+            ("connected_advisors", self.connected_advisors),
+            ("linked_soldiers", self.linked_soldiers),
+            ("crossed_soldier", self.crossed_soldier),
+            ("unscreened_cannon", self.unscreened_cannon),
+            ("open_file_chariot", self.open_file_chariot),
+            ("doubled_cannons", self.doubled_cannons),
+        ]
+    }
+
+    /// Weights in file order, for the tuner
+    pub fn weights_mut(&mut self) -> [&mut [i32; 2]; 8] {
+        [
+            &mut self.blocked_horse_leg,
+            &mut self.connected_elephants,
+            &mut self.connected_advisors,
+            &mut self.linked_soldiers,
+            &mut self.crossed_soldier,
+            &mut self.unscreened_cannon,
+            &mut self.open_file_chariot,
+            &mut self.doubled_cannons,
+        ]
+    }
+}
+
+impl KingSafety {
+    /// Whether any term is weighted, the danger pass is skipped otherwise
+    pub fn has_weights(&self) -> bool {
+        self.named().iter().any(|(_, weight)| *weight != [0, 0])
+    }
+
+    /// Weights in file order, with their json names
+    pub fn named(&self) -> [(&'static str, [i32; 2]); 9] {
+        [
+            ("missing_advisor", self.missing_advisor),
+            ("missing_elephant", self.missing_elephant),
+            ("palace_attacker", self.palace_attacker),
+            ("screened_cannon", self.screened_cannon),
+            ("hollow_cannon", self.hollow_cannon),
+            ("wo_cao_horse", self.wo_cao_horse),
+            ("gua_jiao_horse", self.gua_jiao_horse),
+            ("open_chariot", self.open_chariot),
+            ("iron_bolt", self.iron_bolt),
+        ]
+    }
+
+    /// Weights in file order, for the tuner
+    pub fn weights_mut(&mut self) -> [&mut [i32; 2]; 9] {
+        [
+            &mut self.missing_advisor,
+            &mut self.missing_elephant,
+            &mut self.palace_attacker,
+            &mut self.screened_cannon,
+            &mut self.hollow_cannon,
+            &mut self.wo_cao_horse,
+            &mut self.gua_jiao_horse,
+            &mut self.open_chariot,
+            &mut self.iron_bolt,
+        ]
+    }
+}
+
+#[derive(Debug)]
+pub enum ParamError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Version(u32),
+    Invalid(String),
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamError::Io(err) => write!(f, "cannot read params: {}", err),
+            ParamError::Json(err) => write!(f, "malformed params: {}", err),
+            ParamError::Version(version) => write!(f, "params version {} is not supported, expected {} to {}", version, EvalParams::MinVersion, EvalParams::Version),
+            ParamError::Invalid(msg) => write!(f, "invalid params: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+impl From<io::Error> for ParamError {
+    fn from(err: io::Error) -> Self {
+        ParamError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ParamError {
+    fn from(err: serde_json::Error) -> Self {
+        ParamError::Json(err)
+    }
+}
+
+impl EvalParams {
+    pub const Version: u32 = 3;
+    // oldest version still read, missing terms get their defaults
+    pub const MinVersion: u32 = 1;
+
+    // pieces of each kind per side at the start, for the total phase
+    const StartCounts: [i32; 7] = [2, 2, 2, 2, 1, 2, 5];
+    // keeps tuned values far from overflowing the tapered sum
+    const MaxValue: i32 = 10000;
+
+    /// Tuned parameters compiled into the binary, parsed on first use
+    pub fn defaults() -> &'static EvalParams {
+        static Defaults: OnceLock<EvalParams> = OnceLock::new();
+        Defaults.get_or_init(|| {
+            Self::from_json(include_str!("../../required/params.json")).unwrap_or_else(|err| panic!("embedded {}", err))
+        })
+    }
+
+    pub fn load(path: &str) -> Result<Self, ParamError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, ParamError> {
+        let params: Self = serde_json::from_str(text)?;
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+
+    /// Pretty json with one table row per line
+    pub fn to_json(&self) -> String {
+        let list = |values: &[i32]| values.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(", ");
+        let pst = |pst: &Pst| {
+            let tables = pst.iter().map(|table| {
+                let rows = table.iter().map(|row| format!("      [{}]", list(row))).collect::<Vec<String>>();
+                format!("    [\n{}\n    ]", rows.join(",\n"))
+            }).collect::<Vec<String>>();
+            format!("[\n{}\n  ]", tables.join(",\n"))
+        };
+
+        let weights = |named: &[(&str, [i32; 2])]| {
+            named.iter()
+                .map(|(name, weight)| format!("    \"{}\": [{}]", name, list(weight)))
+                .collect::<Vec<String>>()
+                .join(",\n")
+        };
+
+        format!(
+            "{{\n  \"version\": {},\n  \"tempo\": {},\n  \"mobility_mg\": [{}],\n  \"mobility_eg\": [{}],\n  \"phase\": [{}],\n  \"king_safety\": {{\n{}\n  }},\n  \"structure\": {{\n{}\n  }},\n  \"mg_pst\": {},\n  \"eg_pst\": {}\n}}\n",
+            self.version, self.tempo, list(&self.mobility_mg), list(&self.mobility_eg), list(&self.phase),
+            weights(&self.king_safety.named()), weights(&self.structure.named()), pst(&self.mg_pst), pst(&self.eg_pst),
+        )
+    }
+
+    /// Hash of every value, evals made with different params do not share it
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        self.to_json().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Phase of the starting position, the phase weights must not all be zero
+    pub fn total_phase(phase: &[i32; 7]) -> i32 {
+        2 * phase.iter().zip(Self::StartCounts).map(|(weight, count)| weight * count).sum::<i32>()
+    }
+
+    pub fn validate(&self) -> Result<(), ParamError> {
+        if self.version < Self::MinVersion || self.version > Self::Version {
+            return Err(ParamError::Version(self.version));
+        }
+
+        let in_range = |name: &str, value: i32| {
+            if value.abs() > Self::MaxValue {
+                return Err(ParamError::Invalid(format!("{} is {}, beyond ±{}", name, value, Self::MaxValue)));
+            }
+            Ok(())
+        };
+
+        in_range("tempo", self.tempo)?;
+        for piece in 0..7 {
+            in_range(&format!("mobility_mg[{}]", piece), self.mobility_mg[piece])?;
+            in_range(&format!("mobility_eg[{}]", piece), self.mobility_eg[piece])?;
+            if self.phase[piece] < 0 {
+                return Err(ParamError::Invalid(format!("phase[{}] is negative", piece)));
+            }
+        }
+        if Self::total_phase(&self.phase) == 0 {
+            return Err(ParamError::Invalid("phase weights are all zero".to_string()));
+        }
+
+        for (name, weight) in self.king_safety.named() {
+            in_range(&format!("king_safety.{}[0]", name), weight[0])?;
+            in_range(&format!("king_safety.{}[1]", name), weight[1])?;
+        }
+        for (name, weight) in self.structure.named() {
+            in_range(&format!("structure.{}[0]", name), weight[0])?;
+            in_range(&format!("structure.{}[1]", name), weight[1])?;
+        }
+
+        for (name, pst) in [("mg_pst", &self.mg_pst), ("eg_pst", &self.eg_pst)] {
+            for (piece, table) in pst.iter().enumerate() {
+                for (row, values) in table.iter().enumerate() {
+                    for (col, value) in values.iter().enumerate() {
+                        in_range(&format!("{}[{}][{}][{}]", name, piece, row, col), *value)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn mg_pst_vec(&self) -> Vec<Vec<Vec<i32>>> {
+        Self::pst_vec(&self.mg_pst)
+    }
+
+    pub fn eg_pst_vec(&self) -> Vec<Vec<Vec<i32>>> {
+        Self::pst_vec(&self.eg_pst)
+    }
+
+    fn pst_vec(pst: &Pst) -> Vec<Vec<Vec<i32>>> {
+        pst.iter().map(|table| table.iter().map(|row| row.to_vec()).collect()).collect()
+    }
+}
@@ -0,0 +1,109 @@
+use crate::board::board::Board;
+use crate::board::movee::Move;
+use crate::engine::eval::Eval;
+
+/// A static evaluation the search can use, scores are from the side to move.
+///
+/// The search calls `on_move` right after making a move (null moves included) and
+/// `on_unmove` right after taking it back, so evaluators can keep incremental state.
+pub trait Evaluator {
+    fn evaluate(&mut self, board: &mut Board) -> i32;
+
+    fn on_move(&mut self, _board: &Board, _mov: &Move) {}
+
+    fn on_unmove(&mut self, _board: &Board, _mov: &Move) {}
+
+    /// Called when a search starts on a new position, rebuilds incremental state
+    fn refresh(&mut self, _board: &Board) {}
+
+    /// Identifies the weights, saved tables only load under the same evaluator
+    fn fingerprint(&self) -> u64;
+}
+
+impl Evaluator for Eval {
+    fn evaluate(&mut self, board: &mut Board) -> i32 {
+        Eval::evaluate(self, board)
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.fingerprint
+    }
+}
+
+// lets the evaluator be chosen at runtime
+impl<E: Evaluator + ?Sized> Evaluator for Box<E> {
+    fn evaluate(&mut self, board: &mut Board) -> i32 {
+        (**self).evaluate(board)
+    }
+
+    fn on_move(&mut self, board: &Board, mov: &Move) {
+        (**self).on_move(board, mov)
+    }
+
+    fn on_unmove(&mut self, board: &Board, mov: &Move) {
+        (**self).on_unmove(board, mov)
+    }
+
+    fn refresh(&mut self, board: &Board) {
+        (**self).refresh(board)
+    }
+
+    fn fingerprint(&self) -> u64 {
+        (**self).fingerprint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::condition::Condition::RED;
+    use crate::board::piece::Piece;
+    use crate::engine::eval_params::EvalParams;
+    use crate::engine::search::Engine;
+
+    /// Counts material only, to test the search without positional terms
+    #[derive(Clone, Copy, Default)]
+    struct MaterialEval;
+
+    impl MaterialEval {
+        // indexed by piece - 1: advisor, cannon, chariot, elephant, general, horse, soldier
+        const PieceValues: [i32; 7] = [20, 45, 90, 20, 0, 40, 10];
+    }
+
+    impl Evaluator for MaterialEval {
+        fn evaluate(&mut self, board: &mut Board) -> i32 {
+            let sign = if board.player == RED { 1 } else { -1 };
+
+            let mut score = 0;
+            for row in board.state.iter() {
+                for cell in row.iter() {
+                    if *cell != Piece::SPACE {
+                        score += cell.signum() as i32 * Self::PieceValues[cell.unsigned_abs() as usize - 1];
+                    }
+                }
+            }
+
+            sign * score
+        }
+
+        fn fingerprint(&self) -> u64 {
+            // "material"
+            0x6d61_7465_7269_616c
+        }
+    }
+
+    #[test]
+    fn swapped_evaluator_scores_the_search() {
+        let mut engine: Engine<Box<dyn Evaluator>> = Engine::with_evaluator(Box::new(Eval::from_params(EvalParams::defaults())));
+        let (_, handcrafted) = engine.search(&mut Board::new(), 2, 100000);
+
+        // cached evals belong to the old evaluator
+        engine.evaluator = Box::new(MaterialEval);
+        engine.clear_hash();
+        let (_, material) = engine.search(&mut Board::new(), 2, 100000);
+
+        // nothing can be won in two plies from the start, material alone scores it level
+        assert_eq!(material, 0);
+        assert_ne!(handcrafted, material);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::board::board::Board;
+use crate::board::movee::Move;
+
+// pieces are -7..=7, 15 slots with the empty square unused
+const Pieces: usize = 15;
+const Squares: usize = Board::ROWS * Board::COLS;
+
+/// Scores are kept in -Limit..=Limit by the gravity update
+pub const Limit: i32 = 16384;
+
+/// Moves the entry towards the bonus sign, slower the closer it already is to the limit
+fn gravity(entry: &mut i16, bonus: i32) {
+    let bonus = bonus.clamp(-Limit, Limit);
+    let value = *entry as i32;
+    *entry = (value + bonus - value * bonus.abs() / Limit) as i16;
+}
+
+/// Bonus for a move that caused a cutoff at `depth`, searched moves before it get the negative
+pub fn bonus(depth: i32) -> i32 {
+    (depth * depth * 16).min(1600)
+}
+
+/// A move by a piece to a square, what history tables are indexed by
+#[derive(Clone, Copy, Default)]
+pub struct PieceTo {
+    pub piece: i8,
+    pub to: usize,
+}
+
+impl PieceTo {
+    /// The piece on the start square, call before the move is made
+    pub fn new(game: &Board, mov: &Move) -> Self {
+        Self {
+            piece: game.state[mov.starty as usize][mov.startx as usize],
+            to: mov.end_sq(),
+        }
+    }
+
+    /// Null moves and missing plies, nothing to follow up on
+    pub fn is_none(&self) -> bool {
+        self.piece == 0
+    }
+
+    fn index(&self) -> usize {
+        (self.piece + 7) as usize * Squares + self.to
+    }
+}
+
+/// Score of a move given an earlier move, indexed [earlier piece][earlier to][piece][to]
+pub struct ContinuationHistory {
+    table: Vec<i16>,
+}
+
+impl Default for ContinuationHistory {
+    fn default() -> Self {
+        Self { table: vec![0; Pieces * Squares * Pieces * Squares] }
+    }
+}
+
+impl ContinuationHistory {
+    pub fn get(&self, earlier: PieceTo, mov: PieceTo) -> i32 {
+        if earlier.is_none() {
+            return 0;
+        }
+        self.table[earlier.index() * Pieces * Squares + mov.index()] as i32
+    }
+
+    pub fn update(&mut self, earlier: PieceTo, mov: PieceTo, bonus: i32) {
+        if earlier.is_none() {
+            return;
+        }
+        gravity(&mut self.table[earlier.index() * Pieces * Squares + mov.index()], bonus);
+    }
+}
+
+/// Score of a capture, indexed [attacker][to][victim]
+pub struct CaptureHistory {
+    table: Vec<i16>,
+}
+
+impl Default for CaptureHistory {
+    fn default() -> Self {
+        Self { table: vec![0; Pieces * Squares * 8] }
+    }
+}
+
+impl CaptureHistory {
+    pub fn get(&self, attacker: PieceTo, victim: i8) -> i32 {
+        self.table[attacker.index() * 8 + victim.unsigned_abs() as usize] as i32
+    }
+
+    pub fn update(&mut self, attacker: PieceTo, victim: i8, bonus: i32) {
+        gravity(&mut self.table[attacker.index() * 8 + victim.unsigned_abs() as usize], bonus);
+    }
+}
@@ -0,0 +1,184 @@
+use fnv::FnvHashMap;
+use crate::board::board::Board;
+use crate::board::condition::Condition::DRAW;
+use crate::board::movee::Move;
+
+/// Outcome of a mate search
+#[derive(Clone, Debug)]
+pub enum MateResult {
+    /// Forced mate, the line alternates attacker and defender moves and ends with the mating move
+    Mate(Vec<Move>),
+    /// Every attacker try within the move limit was refuted
+    NoMate,
+    /// The position limit ran out before either was proven
+    Unknown,
+}
+
+impl MateResult {
+    pub fn display(&self) -> String {
+        match self {
+            MateResult::Mate(line) => format!(
+                "Mate in {}: {}",
+                (line.len() + 1) / 2,
+                line.iter().map(Move::display).collect::<Vec<String>>().join(",")
+            ),
+            MateResult::NoMate => "No mate".to_string(),
+            MateResult::Unknown => "Unknown".to_string(),
+        }
+    }
+}
+
+/// The position limit was hit
+struct Aborted;
+
+/// Proves or refutes forced mates for the side to move.
+///
+/// Both sides play every legal move, nothing is pruned by evaluation, so a `NoMate`
+/// answer is a proof. Mates include positions where the defender has no legal move.
+///
+/// The only shortcut is the cache of positions already proven to have no mate within some
+/// number of moves. Quiet attacker moves cannot be cut on the last move either, since leaving
+/// the defender without a legal move also wins in xiangqi.
+pub struct MateSolver {
+    pub maxpositions: i64,
+    pub positions: i64,
+
+    // hash -> number of attacker moves proven not to be enough
+    no_mate: FnvHashMap<u64, i32>,
+    // repetition draws seen, results that depend on one are not cached
+    draws: u64,
+}
+
+impl MateSolver {
+    pub const DefaultPositions: i64 = 50_000_000;
+
+    pub fn new(maxpositions: i64) -> Self {
+        Self {
+            maxpositions,
+            positions: 0,
+            no_mate: FnvHashMap::default(),
+            draws: 0,
+        }
+    }
+
+    /// Searches for a mate in at most `n` attacker moves
+    pub fn solve(&mut self, game: &mut Board, n: i32) -> MateResult {
+        self.positions = 0;
+
+        if game.is_draw() {
+            return MateResult::NoMate;
+        }
+
+        match self.attack(game, n) {
+            Ok(Some(line)) => MateResult::Mate(line),
+            Ok(None) => MateResult::NoMate,
+            Err(Aborted) => MateResult::Unknown,
+        }
+    }
+
+    /// Finds the shortest mate within `n` attacker moves
+    fn attack(&mut self, game: &mut Board, n: i32) -> Result<Option<Vec<Move>>, Aborted> {
+        for moves in 1..=n {
+            if let Some(line) = self.attack_exact(game, moves)? {
+                return Ok(Some(line));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Finds a mate using exactly up to `n` attacker moves, without preferring shorter ones
+    fn attack_exact(&mut self, game: &mut Board, n: i32) -> Result<Option<Vec<Move>>, Aborted> {
+        self.positions += 1;
+        if self.positions >= self.maxpositions {
+            return Err(Aborted);
+        }
+
+        if game.is_draw() {
+            self.draws += 1;
+            return Ok(None);
+        }
+
+        let hash = game.get_hash();
+        if self.no_mate.get(&hash).is_some_and(|proven| *proven >= n) {
+            return Ok(None);
+        }
+
+        let draws = self.draws;
+        let mut moves = Self::order_moves(game);
+        for mov in moves.iter_mut() {
+            game.mov(mov);
+            let line = self.defend(game, n);
+            game.unmov(mov);
+
+            if let Some(mut line) = line? {
+                line.insert(0, mov.clone());
+                return Ok(Some(line));
+            }
+        }
+
+        // a repetition on this path may have refuted a mate that works elsewhere
+        if self.draws == draws {
+            let proven = self.no_mate.entry(hash).or_insert(0);
+            *proven = (*proven).max(n);
+        }
+
+        Ok(None)
+    }
+
+    /// The attacker just moved with `n` moves left including it,
+    /// returns the longest resistance if every defence is mated
+    fn defend(&mut self, game: &mut Board, n: i32) -> Result<Option<Vec<Move>>, Aborted> {
+        self.positions += 1;
+        if self.positions >= self.maxpositions {
+            return Err(Aborted);
+        }
+
+        let cond = game.condition();
+        if cond == game.player.inverse() {
+            return Ok(Some(vec![]));
+        } else if cond == DRAW {
+            self.draws += 1;
+            return Ok(None);
+        }
+
+        if n <= 1 {
+            return Ok(None);
+        }
+
+        let mut longest: Option<Vec<Move>> = None;
+        for mov in game.get_moves(false).iter_mut() {
+            game.mov(mov);
+            let line = self.attack(game, n - 1);
+            game.unmov(mov);
+
+            match line? {
+                None => return Ok(None),
+                Some(mut line) => {
+                    if longest.as_ref().is_none_or(|best| line.len() + 1 > best.len()) {
+                        line.insert(0, mov.clone());
+                        longest = Some(line);
+                    }
+                }
+            }
+        }
+
+        Ok(longest)
+    }
+
+    /// Checks first, then captures, then quiet moves
+    fn order_moves(game: &mut Board) -> Vec<Move> {
+        let mut moves = game.get_moves(false);
+        let mut keys = Vec::with_capacity(moves.len());
+        for mov in moves.iter_mut() {
+            game.mov(mov);
+            let check = game.is_check();
+            game.unmov(mov);
+            keys.push((!check, mov.is_quiet()));
+        }
+
+        let mut ordered: Vec<(Move, (bool, bool))> = moves.into_iter().zip(keys).collect();
+        ordered.sort_by_key(|(_, key)| *key);
+        ordered.into_iter().map(|(mov, _)| mov).collect()
+    }
+}
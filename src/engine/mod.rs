@@ -1,9 +1,19 @@
 pub mod search;
 pub mod eval;
+pub mod eval_params;
+pub mod endgame;
+pub mod evaluator;
 pub mod training;
+pub mod stats;
+pub mod mate;
+pub mod pns;
+pub mod skill;
+pub mod nnue;
 
 mod tt;
-mod parameters;
+mod eval_cache;
+mod history;
+pub mod parameters;
 
 
 
@@ -0,0 +1,484 @@
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufReader, Read};
+use std::sync::Arc;
+use fnv::FnvHasher;
+use crate::board::board::Board;
+use crate::board::condition::Condition;
+use crate::board::condition::Condition::{BLACK, RED};
+use crate::board::movee::Move;
+use crate::board::piece::Piece;
+use crate::engine::evaluator::Evaluator;
+
+/// Saved network layout, all little endian:
+/// magic, format version (u32), feature count (u32), hidden size (u32), output scale (i32),
+/// then feature weights [feature][hidden] (i16), feature biases (i16),
+/// output weights for the side to move then the other side (i8), and the output bias (i32)
+const FileMagic: &[u8; 4] = b"XQNN";
+const FileVersion: u32 = 1;
+const HeaderBytes: u64 = 20;
+
+// one bucket per palace square of the own general
+const KingBuckets: usize = 9;
+// 7 piece kinds for each side, relative to the perspective
+const PieceKinds: usize = 14;
+const Squares: usize = Board::ROWS * Board::COLS;
+
+/// HalfKA features: every piece including both generals, per own general square
+pub const Features: usize = KingBuckets * PieceKinds * Squares;
+
+// clipped relu range of the hidden layer, and the output weight scale
+const QA: i32 = 255;
+const QB: i32 = 64;
+
+/// Quantized network weights, shared by every evaluator using them
+pub struct Network {
+    hidden: usize,
+    ft_weights: Vec<i16>,
+    ft_biases: Vec<i16>,
+    out_weights: Vec<i8>,
+    out_bias: i32,
+    // converts the network output to eval units
+    scale: i32,
+
+    // use the avx2 code paths, detected when loading
+    pub simd: bool,
+}
+
+impl Network {
+    /// Loads a network written by `save`
+    pub fn load(path: &str) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != FileMagic {
+            return Err(invalid("not a network file"));
+        }
+
+        let mut word = [0u8; 4];
+        input.read_exact(&mut word)?;
+        if u32::from_le_bytes(word) != FileVersion {
+            return Err(invalid("unsupported network file version"));
+        }
+
+        input.read_exact(&mut word)?;
+        if u32::from_le_bytes(word) as usize != Features {
+            return Err(invalid("network was trained for different features"));
+        }
+
+        input.read_exact(&mut word)?;
+        let hidden = u32::from_le_bytes(word) as usize;
+        if hidden == 0 || !hidden.is_multiple_of(16) || hidden > 4096 {
+            return Err(invalid("hidden size must be a multiple of 16, at most 4096"));
+        }
+
+        input.read_exact(&mut word)?;
+        let scale = i32::from_le_bytes(word);
+
+        let length = std::fs::metadata(path)?.len();
+        if length != HeaderBytes + Self::body_bytes(hidden) {
+            return Err(invalid("network file size does not match its header"));
+        }
+
+        let mut read_i16 = |count: usize| -> io::Result<Vec<i16>> {
+            let mut bytes = vec![0u8; count * 2];
+            input.read_exact(&mut bytes)?;
+            Ok(bytes.chunks_exact(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect())
+        };
+        let ft_weights = read_i16(Features * hidden)?;
+        let ft_biases = read_i16(hidden)?;
+
+        let mut bytes = vec![0u8; 2 * hidden];
+        input.read_exact(&mut bytes)?;
+        let out_weights = bytes.iter().map(|byte| *byte as i8).collect();
+
+        input.read_exact(&mut word)?;
+        let out_bias = i32::from_le_bytes(word);
+
+        Ok(Self {
+            hidden,
+            ft_weights,
+            ft_biases,
+            out_weights,
+            out_bias,
+            scale,
+            simd: Self::detect_simd(),
+        })
+    }
+
+    /// Writes the network in the format `load` reads
+    #[cfg(test)]
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        use std::io::{BufWriter, Write};
+
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(FileMagic)?;
+        out.write_all(&FileVersion.to_le_bytes())?;
+        out.write_all(&(Features as u32).to_le_bytes())?;
+        out.write_all(&(self.hidden as u32).to_le_bytes())?;
+        out.write_all(&self.scale.to_le_bytes())?;
+
+        for value in self.ft_weights.iter().chain(self.ft_biases.iter()) {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        for value in self.out_weights.iter() {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        out.write_all(&self.out_bias.to_le_bytes())?;
+
+        out.flush()
+    }
+
+    /// Builds a network from raw weights, the layouts are the ones in the file
+    #[cfg(test)]
+    pub fn from_weights(hidden: usize, ft_weights: Vec<i16>, ft_biases: Vec<i16>,
+                        out_weights: Vec<i8>, out_bias: i32, scale: i32) -> Result<Self, String> {
+        if hidden == 0 || !hidden.is_multiple_of(16) {
+            return Err("hidden size must be a multiple of 16".to_string());
+        }
+        if ft_weights.len() != Features * hidden || ft_biases.len() != hidden || out_weights.len() != 2 * hidden {
+            return Err("weight sizes do not match the hidden size".to_string());
+        }
+
+        Ok(Self { hidden, ft_weights, ft_biases, out_weights, out_bias, scale, simd: Self::detect_simd() })
+    }
+
+    /// Hash of every weight, to tell networks apart
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        self.ft_weights.hash(&mut hasher);
+        self.ft_biases.hash(&mut hasher);
+        self.out_weights.hash(&mut hasher);
+        self.out_bias.hash(&mut hasher);
+        self.scale.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn body_bytes(hidden: usize) -> u64 {
+        (Features * hidden * 2 + hidden * 2 + hidden * 2 + 4) as u64
+    }
+
+    fn detect_simd() -> bool {
+        #[cfg(target_arch = "x86_64")]
+        {
+            is_x86_feature_detected!("avx2")
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            false
+        }
+    }
+
+    fn feature_row(&self, feature: usize) -> &[i16] {
+        &self.ft_weights[feature * self.hidden..(feature + 1) * self.hidden]
+    }
+
+    /// Adds (or subtracts) a feature's weights to an accumulator
+    fn apply(&self, values: &mut [i16], feature: usize, add: bool) {
+        let row = self.feature_row(feature);
+
+        #[cfg(target_arch = "x86_64")]
+        if self.simd {
+            // SAFETY: avx2 support was checked when the network was built
+            unsafe { simd::apply(values, row, add) };
+            return;
+        }
+
+        for (value, weight) in values.iter_mut().zip(row) {
+            *value = if add { value.wrapping_add(*weight) } else { value.wrapping_sub(*weight) };
+        }
+    }
+
+    /// Output from both accumulators, in eval units for the side to move
+    fn output(&self, us: &[i16], them: &[i16]) -> i32 {
+        let (our_weights, their_weights) = self.out_weights.split_at(self.hidden);
+
+        #[cfg(target_arch = "x86_64")]
+        let sum = if self.simd {
+            // SAFETY: avx2 support was checked when the network was built
+            unsafe { simd::crelu_dot(us, our_weights) + simd::crelu_dot(them, their_weights) }
+        } else {
+            Self::crelu_dot(us, our_weights) + Self::crelu_dot(them, their_weights)
+        };
+        #[cfg(not(target_arch = "x86_64"))]
+        let sum = Self::crelu_dot(us, our_weights) + Self::crelu_dot(them, their_weights);
+
+        // the scaled sum can pass i32 for large outputs
+        ((sum as i64 + self.out_bias as i64) * self.scale as i64 / (QA * QB) as i64) as i32
+    }
+
+    fn crelu_dot(values: &[i16], weights: &[i8]) -> i32 {
+        values.iter()
+            .zip(weights)
+            .map(|(value, weight)| (*value as i32).clamp(0, QA) * *weight as i32)
+            .sum()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+
+    /// Hidden sizes are multiples of 16, one register of i16 per step
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn apply(values: &mut [i16], row: &[i16], add: bool) {
+        for (value, weight) in values.chunks_exact_mut(16).zip(row.chunks_exact(16)) {
+            let a = _mm256_loadu_si256(value.as_ptr() as *const __m256i);
+            let b = _mm256_loadu_si256(weight.as_ptr() as *const __m256i);
+            let result = if add { _mm256_add_epi16(a, b) } else { _mm256_sub_epi16(a, b) };
+            _mm256_storeu_si256(value.as_mut_ptr() as *mut __m256i, result);
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn crelu_dot(values: &[i16], weights: &[i8]) -> i32 {
+        let zero = _mm256_setzero_si256();
+        let limit = _mm256_set1_epi16(super::QA as i16);
+        let mut sums = _mm256_setzero_si256();
+
+        for (value, weight) in values.chunks_exact(16).zip(weights.chunks_exact(16)) {
+            let v = _mm256_loadu_si256(value.as_ptr() as *const __m256i);
+            let clipped = _mm256_max_epi16(_mm256_min_epi16(v, limit), zero);
+            let w = _mm256_cvtepi8_epi16(_mm_loadu_si128(weight.as_ptr() as *const __m128i));
+            sums = _mm256_add_epi32(sums, _mm256_madd_epi16(clipped, w));
+        }
+
+        let mut lanes = [0i32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sums);
+        lanes.iter().sum()
+    }
+}
+
+/// Hidden layer values of one position, for each perspective
+#[derive(Clone)]
+struct Accumulator {
+    values: [Vec<i16>; 2],
+}
+
+/// Evaluates with a network, keeping one accumulator per ply of the current line
+pub struct Nnue {
+    net: Arc<Network>,
+    stack: Vec<Accumulator>,
+    // accumulators in use, the last one is the current position
+    len: usize,
+}
+
+impl Nnue {
+    pub fn new(net: Arc<Network>) -> Self {
+        Self { net, stack: vec![], len: 0 }
+    }
+
+    /// Feature of a piece on a square, seen by `perspective` with its general in `bucket`
+    fn feature(perspective: Condition, bucket: usize, piece: i8, row: usize, col: usize) -> usize {
+        let (row, col) = if perspective == RED { (row, col) } else { Move::flip_coord(&(row, col)) };
+        let own = (piece > 0) == (perspective == RED);
+        let kind = piece.unsigned_abs() as usize - 1 + if own { 0 } else { 7 };
+        (bucket * PieceKinds + kind) * Squares + row * Board::COLS + col
+    }
+
+    /// Palace square of the perspective's own general
+    fn king_bucket(board: &Board, perspective: Condition) -> usize {
+        let row = board.general[2 * perspective as usize] as usize;
+        let col = board.general[2 * perspective as usize + 1] as usize;
+        let (row, col) = if perspective == RED { (row, col) } else { Move::flip_coord(&(row, col)) };
+        (row - 7) * 3 + (col - 3)
+    }
+
+    /// Recomputes one perspective of an accumulator from the board
+    fn refresh_perspective(net: &Network, values: &mut [i16], board: &Board, perspective: Condition) {
+        values.copy_from_slice(&net.ft_biases);
+        let bucket = Self::king_bucket(board, perspective);
+        for row in 0..Board::ROWS {
+            for col in 0..Board::COLS {
+                let piece = board.state[row][col];
+                if piece != Piece::SPACE {
+                    net.apply(values, Self::feature(perspective, bucket, piece, row, col), true);
+                }
+            }
+        }
+    }
+
+    /// Makes room for one more accumulator, reusing old allocations
+    fn push(&mut self) {
+        if self.len == self.stack.len() {
+            let empty = vec![0; self.net.hidden];
+            self.stack.push(Accumulator { values: [empty.clone(), empty] });
+        }
+        self.len += 1;
+    }
+}
+
+impl Evaluator for Nnue {
+    fn evaluate(&mut self, board: &mut Board) -> i32 {
+        if self.len == 0 {
+            self.refresh(board);
+        }
+
+        let accumulator = &self.stack[self.len - 1];
+        let us = &accumulator.values[board.player as usize];
+        let them = &accumulator.values[board.player.inverse() as usize];
+        self.net.output(us, them)
+    }
+
+    fn on_move(&mut self, board: &Board, mov: &Move) {
+        if self.len == 0 {
+            self.refresh(board);
+            return;
+        }
+
+        self.push();
+        let (done, rest) = self.stack.split_at_mut(self.len - 1);
+        let previous = &done[self.len - 2];
+        let current = &mut rest[0];
+        for perspective in [RED, BLACK] {
+            current.values[perspective as usize].copy_from_slice(&previous.values[perspective as usize]);
+        }
+
+        if mov.is_null() {
+            return;
+        }
+
+        let (start_row, start_col) = (mov.starty as usize, mov.startx as usize);
+        let (end_row, end_col) = (mov.endy as usize, mov.endx as usize);
+        let piece = board.state[end_row][end_col];
+        let mover = if piece > 0 { RED } else { BLACK };
+
+        for perspective in [RED, BLACK] {
+            let values = &mut current.values[perspective as usize];
+
+            // the own general changed bucket, every feature changes
+            if piece.abs() == Piece::GENERAL && mover == perspective {
+                Self::refresh_perspective(&self.net, values, board, perspective);
+                continue;
+            }
+
+            let bucket = Self::king_bucket(board, perspective);
+            self.net.apply(values, Self::feature(perspective, bucket, piece, start_row, start_col), false);
+            self.net.apply(values, Self::feature(perspective, bucket, piece, end_row, end_col), true);
+            if mov.captured != Piece::SPACE {
+                self.net.apply(values, Self::feature(perspective, bucket, mov.captured, end_row, end_col), false);
+            }
+        }
+    }
+
+    fn on_unmove(&mut self, _board: &Board, _mov: &Move) {
+        self.len = self.len.saturating_sub(1);
+    }
+
+    fn refresh(&mut self, board: &Board) {
+        self.len = 0;
+        self.push();
+        for perspective in [RED, BLACK] {
+            Self::refresh_perspective(&self.net, &mut self.stack[0].values[perspective as usize], board, perspective);
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        self.net.fingerprint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use super::*;
+
+    const Hidden: usize = 32;
+
+    fn random_network(seed: u64) -> Network {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let ft_weights = (0..Features * Hidden).map(|_| rng.gen_range(-64..=64)).collect();
+        let ft_biases = (0..Hidden).map(|_| rng.gen_range(-128..=128)).collect();
+        let out_weights = (0..2 * Hidden).map(|_| rng.gen_range(-127..=127)).collect();
+        Network::from_weights(Hidden, ft_weights, ft_biases, out_weights, rng.gen_range(-5000..=5000), 400).unwrap()
+    }
+
+    fn full_eval(net: &Arc<Network>, board: &mut Board) -> i32 {
+        let mut fresh = Nnue::new(net.clone());
+        fresh.refresh(board);
+        fresh.evaluate(board)
+    }
+
+    #[test]
+    fn scalar_and_simd_agree() {
+        let mut scalar = random_network(1);
+        scalar.simd = false;
+        let simd = random_network(1);
+        if !simd.simd {
+            // no avx2 on this machine, nothing to compare
+            return;
+        }
+
+        let mut ours = scalar.ft_biases.clone();
+        let mut theirs = scalar.ft_biases.clone();
+        for (feature, add) in [(0, true), (17, true), (4000, true), (Features - 1, true), (17, false), (123, false)] {
+            let mut expected = ours.clone();
+            scalar.apply(&mut expected, feature, add);
+            simd.apply(&mut ours, feature, add);
+            assert_eq!(ours, expected);
+
+            simd.apply(&mut theirs, Features - 1 - feature, !add);
+            assert_eq!(simd.output(&ours, &theirs), scalar.output(&ours, &theirs));
+        }
+    }
+
+    #[test]
+    fn incremental_matches_refresh() {
+        let net = Arc::new(random_network(2));
+        let mut board = Board::new();
+        let mut nnue = Nnue::new(net.clone());
+        nnue.refresh(&board);
+
+        // includes captures and general moves, which refresh a perspective
+        let line = "H3H7,G7G6,B3E3,HXG8,B1C3,BXC8,A1B1,G8F6,C4C5,IXI9,E3G3,AXBX,G4G5,G6G5,G3GX,FXE9,\
+                    H7C7,E7E6,B1B6,I9IX,GXG6,C8E7,G6E6,B8G8,B6BX,G8G1,E1E2,G1I1";
+        let mut played = vec![];
+        for text in line.split(",") {
+            let mut mov = Move::from_string(text).unwrap();
+            assert!(board.try_move(&mut mov), "illegal move {}", text);
+            nnue.on_move(&board, &mov);
+            assert_eq!(nnue.evaluate(&mut board), full_eval(&net, &mut board), "after {}", text);
+            played.push(mov);
+        }
+
+        while let Some(mut mov) = played.pop() {
+            board.unmov(&mut mov);
+            nnue.on_unmove(&board, &mov);
+            assert_eq!(nnue.evaluate(&mut board), full_eval(&net, &mut board));
+        }
+
+        // null moves only change the side to move
+        nnue.on_move(&board, &Move::null());
+        board.mov(&mut Move::null());
+        assert_eq!(nnue.evaluate(&mut board), full_eval(&net, &mut board));
+    }
+
+    #[test]
+    fn output_does_not_overflow() {
+        let net = Network::from_weights(
+            Hidden, vec![0; Features * Hidden], vec![QA as i16; Hidden],
+            vec![127; 2 * Hidden], i32::MAX / 2, 1000,
+        ).unwrap();
+        let values = vec![QA as i16; Hidden];
+        let expected = ((2 * Hidden as i64 * QA as i64 * 127 + i32::MAX as i64 / 2) * 1000 / (QA * QB) as i64) as i32;
+        assert_eq!(net.output(&values, &values), expected);
+    }
+
+    #[test]
+    fn saved_network_loads_back() {
+        let net = random_network(3);
+        let path = std::env::temp_dir().join(format!("xiangqi-nnue-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        net.save(path).unwrap();
+        let loaded = Network::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.hidden, Hidden);
+        assert_eq!(loaded.fingerprint(), net.fingerprint());
+    }
+}
@@ -1,34 +1,25 @@
 use std::cmp::max;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 pub struct SearchParameters;
 
 impl SearchParameters {
-    pub const Window: i32 = 35;
     pub const Checkmate: i32 = 9000;
     pub const Win: i32 = 10000;
-    pub const Size: u64 = 64;
-    pub const Buckets: i32 = 2;
+    // default tt size in megabytes
+    pub const Size: usize = 64;
+    // largest tt size in megabytes a client can ask for
+    pub const MaxSize: usize = 32 * 1024;
+    // entries per tt bucket, one cache line
+    pub const Buckets: usize = 6;
+    // entries in the eval cache, 16 bytes each
+    pub const EvalCacheSize: usize = 1 << 16;
     pub const AlphaFlag: i8 = 1;
     pub const BetaFlag: i8 = 2;
     pub const ExactFlag: i8 = 3;
 
-    pub const StaticNullMovePruningBaseMargin: i32 = 85;
-    pub const NMRDepthLimit: i32 = 3;
-
-    pub const SingularExtensionDepthLimit: i32 = 4;
-    pub const SingularMoveMargin: i32 = 125;
-    pub const SingularMoveExtension: i32 = 1;
-
-    pub const LMRLegalMovesLimit: i32 = 4;
-    pub const LMRDepthLimit: i32 = 3;
-    pub const LateMovePruningMargins: [i32; 6] = [0,8,12,16,20,24];
-
-    pub const FutilityPruningDepthLimit: i32 = 8;
-    pub const FutilityMargins: [i32;9] = [0, 200, 250, 300, 350, 400, 450, 500, 550];
-
-    pub const IIDDepthReduction: i32 = 2;
-    pub const IIDDepthLimit: i32 = 2;
-
     pub const MaxDepth: i32 = 100;
 
     pub const MaxKillers: i32 = 2;
@@ -38,9 +29,189 @@ impl SearchParameters {
     pub const MvvLvaOffset: i32 = 60000 - 256;
     pub const MaxHistoryScore: i32 = Self::MvvLvaOffset - 30;
     pub const PVMoveScore: i32 = 65;
+    pub const CaptureHistoryDivisor: i32 = 256;
+}
+
+/// Returns a copy of `target` with one serialized field replaced, the value is parsed as json
+fn set_field<T: Serialize + DeserializeOwned>(target: &T, name: &str, value: &str) -> Result<T, String> {
+    let mut fields = serde_json::to_value(target).map_err(|e| e.to_string())?;
+    let field = fields.get_mut(name).ok_or(format!("unknown search parameter {}", name))?;
+    *field = serde_json::from_str::<Value>(value).map_err(|e| format!("invalid value for {}: {}", name, e))?;
+
+    serde_json::from_value(fields).map_err(|e| format!("invalid value for {}: {}", name, e))
+}
+
+/// Pruning and reduction settings of the search, changeable at runtime
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchConfig {
+    // aspiration window around the last score
+    pub window: i32,
+
+    // how much the root side dislikes a draw, negative seeks draws
+    pub contempt: i32,
+
+    pub static_null_move_margin: i32,
+
+    // null move reduction is base + depth / divisor
+    pub null_move_depth_limit: i32,
+    pub null_move_base_reduction: i32,
+    pub null_move_depth_divisor: i32,
+
+    pub razoring_depth_limit: i32,
+    // razoring uses futility margin * multiplier
+    pub razoring_margin_multiplier: i32,
+
+    pub singular_extension_depth_limit: i32,
+    pub singular_move_margin: i32,
+    pub singular_move_extension: i32,
+
+    // lmr reduction is max(min, depth / depth divisor) + moves / moves divisor
+    pub lmr_legal_moves_limit: i32,
+    pub lmr_depth_limit: i32,
+    pub lmr_min_reduction: i32,
+    pub lmr_depth_divisor: i32,
+    pub lmr_moves_divisor: i32,
+    // history of the move / divisor is taken off the reduction
+    pub lmr_history_divisor: i32,
+
+    // moves searched per depth before quiet moves are pruned
+    pub late_move_pruning_depth_limit: i32,
+    pub late_move_pruning_margins: Vec<i32>,
+
+    // margins per depth
+    pub futility_pruning_depth_limit: i32,
+    pub futility_margins: Vec<i32>,
+
+    pub iid_depth_reduction: i32,
+    pub iid_depth_limit: i32,
+
+    // quiet moves with history below -margin * depth are pruned
+    pub history_pruning_depth_limit: i32,
+    pub history_pruning_margin: i32,
+
+    // quiet checks are searched in this many plies at the start of qsearch
+    pub qsearch_check_plies: i32,
+}
 
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            window: 35,
+            contempt: 0,
+            static_null_move_margin: 85,
+            null_move_depth_limit: 3,
+            null_move_base_reduction: 1,
+            null_move_depth_divisor: 6,
+            razoring_depth_limit: 2,
+            razoring_margin_multiplier: 3,
+            singular_extension_depth_limit: 4,
+            singular_move_margin: 125,
+            singular_move_extension: 1,
+            lmr_legal_moves_limit: 4,
+            lmr_depth_limit: 3,
+            lmr_min_reduction: 2,
+            lmr_depth_divisor: 4,
+            lmr_moves_divisor: 12,
+            lmr_history_divisor: 8192,
+            late_move_pruning_depth_limit: 5,
+            late_move_pruning_margins: vec![0, 8, 12, 16, 20, 24],
+            futility_pruning_depth_limit: 8,
+            futility_margins: vec![0, 200, 250, 300, 350, 400, 450, 500, 550],
+            iid_depth_reduction: 2,
+            iid_depth_limit: 2,
+            history_pruning_depth_limit: 3,
+            history_pruning_margin: 2048,
+            qsearch_check_plies: 2,
+        }
+    }
+}
+
+impl SearchConfig {
+    /// Sets one field by its name, the value is parsed as json (`35`, `[0, 8, 12]`)
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let config = set_field(self, name, value)?;
+        if config.lmr_depth_divisor <= 0 || config.lmr_moves_divisor <= 0 || config.lmr_history_divisor <= 0
+            || config.null_move_depth_divisor <= 0 {
+            return Err("divisors must be positive".to_string());
+        }
+        if config.futility_margins.is_empty() || config.late_move_pruning_margins.is_empty() {
+            return Err("margins cannot be empty".to_string());
+        }
+
+        *self = config;
+        Ok(())
+    }
+
+    pub fn lmr(&self, depth: i32, cnt: i32) -> i32 {
+        max(self.lmr_min_reduction, depth / self.lmr_depth_divisor) + cnt / self.lmr_moves_divisor
+    }
+
+    pub fn null_move_reduction(&self, depth: i32) -> i32 {
+        self.null_move_base_reduction + depth / self.null_move_depth_divisor
+    }
+
+    /// Futility margin for the depth, depths past the table use the last margin
+    pub fn futility_margin(&self, depth: i32) -> i32 {
+        Self::margin(&self.futility_margins, depth)
+    }
+
+    pub fn late_move_pruning_margin(&self, depth: i32) -> i32 {
+        Self::margin(&self.late_move_pruning_margins, depth)
+    }
+
+    fn margin(margins: &[i32], depth: i32) -> i32 {
+        let index = (depth.max(0) as usize).min(margins.len() - 1);
+        margins[index]
+    }
+}
+
+/// Switches for each search heuristic, to bisect search bugs
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Heuristics {
+    pub null_move: bool,
+    pub static_null_move: bool,
+    pub razoring: bool,
+    pub futility: bool,
+    pub late_move_pruning: bool,
+    pub late_move_reduction: bool,
+    pub singular_extension: bool,
+    pub internal_iterative_deepening: bool,
+    pub check_extension: bool,
+    pub continuation_history: bool,
+    pub capture_history: bool,
+    pub history_pruning: bool,
+}
+
+impl Default for Heuristics {
+    fn default() -> Self {
+        Self {
+            null_move: true,
+            static_null_move: true,
+            razoring: true,
+            futility: true,
+            late_move_pruning: true,
+            late_move_reduction: true,
+            singular_extension: true,
+            internal_iterative_deepening: true,
+            check_extension: true,
+            continuation_history: true,
+            capture_history: true,
+            history_pruning: true,
+        }
+    }
+}
+
+impl Heuristics {
+    /// Checks if `name` is one of the switches
+    pub fn has(&self, name: &str) -> bool {
+        serde_json::to_value(self).map(|fields| fields.get(name).is_some()).unwrap_or(false)
+    }
 
-    pub fn LMR(depth: i32, cnt: i32) -> i32 {
-        (max(2, depth / 4) + cnt / 12)
+    /// Turns one heuristic on or off by name, the value is `true` or `false`
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        *self = set_field(self, name, value)?;
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,290 @@
+use fnv::FnvHashMap;
+use crate::board::board::Board;
+use crate::board::condition::Condition::DRAW;
+use crate::board::movee::Move;
+use crate::engine::mate::MateResult;
+
+/// Proof and disproof numbers of a position, from the attacker's point of view
+#[derive(Clone, Copy)]
+struct PnEntry {
+    pn: u32,
+    dn: u32,
+    // plies to mate once proven
+    dist: u32,
+    // the value relies on a repetition of the path it was found on
+    path_dependent: bool,
+}
+
+impl PnEntry {
+    const Unknown: PnEntry = PnEntry { pn: 1, dn: 1, dist: 0, path_dependent: false };
+    const Repetition: PnEntry = PnEntry { pn: Self::Infinity, dn: 0, dist: 0, path_dependent: true };
+
+    const Infinity: u32 = 100_000_000;
+
+    fn proven(dist: u32) -> Self {
+        PnEntry { pn: 0, dn: Self::Infinity, dist, path_dependent: false }
+    }
+
+    fn disproven() -> Self {
+        PnEntry { pn: Self::Infinity, dn: 0, dist: 0, path_dependent: false }
+    }
+
+    fn is_solved(&self) -> bool {
+        self.pn == 0 || self.dn == 0
+    }
+}
+
+/// Depth-first proof-number search (df-pn) for long checking mates.
+///
+/// The attacker only plays checks, the defender plays every legal move. A position that
+/// repeats the current path counts as a failure for the attacker, since perpetual checks
+/// cannot win; results that relied on such a repetition are not reused on other paths.
+pub struct ProofNumberSearch {
+    pub maxpositions: i64,
+    pub positions: i64,
+
+    table: FnvHashMap<u64, PnEntry>,
+    // hashes of the positions on the current path
+    path: Vec<u64>,
+}
+
+impl ProofNumberSearch {
+    pub const DefaultPositions: i64 = 5_000_000;
+
+    pub fn new(maxpositions: i64) -> Self {
+        Self {
+            maxpositions,
+            positions: 0,
+            table: FnvHashMap::default(),
+            path: vec![],
+        }
+    }
+
+    /// Tries to prove a checking mate for the side to move
+    pub fn solve(&mut self, game: &mut Board) -> MateResult {
+        self.positions = 0;
+        self.path.clear();
+
+        let root = self.mid(game, true, PnEntry::Infinity, PnEntry::Infinity);
+        if root.pn == 0 {
+            MateResult::Mate(self.proof_line(game))
+        } else if root.dn == 0 {
+            MateResult::NoMate
+        } else {
+            MateResult::Unknown
+        }
+    }
+
+    /// Expands the position until its proof or disproof number reaches the threshold
+    fn mid(&mut self, game: &mut Board, attacker: bool, th_pn: u32, th_dn: u32) -> PnEntry {
+        self.positions += 1;
+
+        let mut children = match self.children(game, attacker) {
+            Ok(children) => children,
+            Err(terminal) => {
+                self.table.insert(game.get_hash(), terminal);
+                return terminal;
+            }
+        };
+
+        let hash = game.get_hash();
+        self.path.push(hash);
+
+        let mut values: Vec<PnEntry> = children.iter()
+            .map(|(_, child_hash)| self.lookup(*child_hash))
+            .collect();
+
+        let mut current;
+        loop {
+            current = Self::combine(&values, attacker);
+            if current.pn >= th_pn || current.dn >= th_dn || current.is_solved() {
+                break;
+            }
+
+            if self.positions >= self.maxpositions {
+                break;
+            }
+
+            // most proving child, and the thresholds it gets before the second best takes over
+            let (best, second) = Self::select(&values, attacker);
+            let (child_pn, child_dn) = if attacker {
+                let limit = values.get(second).map_or(PnEntry::Infinity, |v| v.pn.saturating_add(1));
+                (th_pn.min(limit), (th_dn - current.dn).saturating_add(values[best].dn))
+            } else {
+                let limit = values.get(second).map_or(PnEntry::Infinity, |v| v.dn.saturating_add(1));
+                ((th_pn - current.pn).saturating_add(values[best].pn), th_dn.min(limit))
+            };
+
+            let mov = &mut children[best].0;
+            game.mov(mov);
+            values[best] = self.mid(game, !attacker, child_pn.min(PnEntry::Infinity), child_dn.min(PnEntry::Infinity));
+            game.unmov(mov);
+        }
+
+        self.path.pop();
+        self.table.insert(hash, current);
+        current
+    }
+
+    /// Legal children with their hashes, or the value of a terminal position
+    fn children(&mut self, game: &mut Board, attacker: bool) -> Result<Vec<(Move, u64)>, PnEntry> {
+        // repetition and capture rule draws depend on the moves played to get here
+        let cond = game.condition();
+        if cond == DRAW {
+            return Err(PnEntry::Repetition);
+        }
+
+        // no legal moves loses in xiangqi
+        if cond == game.player.inverse() {
+            return Err(if attacker { PnEntry::disproven() } else { PnEntry::proven(0) });
+        }
+
+        let mut children = vec![];
+        for mut mov in game.get_moves(false) {
+            game.mov(&mut mov);
+            if !attacker || game.is_check() {
+                children.push((mov.clone(), game.get_hash()));
+            }
+            game.unmov(&mut mov);
+        }
+
+        if children.is_empty() {
+            // no checks left
+            return Err(PnEntry::disproven());
+        }
+
+        Ok(children)
+    }
+
+    fn lookup(&self, hash: u64) -> PnEntry {
+        if self.path.contains(&hash) {
+            return PnEntry::Repetition;
+        }
+
+        match self.table.get(&hash) {
+            // found through a repetition elsewhere, not valid here
+            Some(entry) if entry.path_dependent => PnEntry::Unknown,
+            Some(entry) => *entry,
+            None => PnEntry::Unknown,
+        }
+    }
+
+    /// Value of a node from its children
+    fn combine(values: &[PnEntry], attacker: bool) -> PnEntry {
+        let (mut pn, mut dn) = if attacker { (PnEntry::Infinity, 0) } else { (0, PnEntry::Infinity) };
+        let mut path_dependent = false;
+
+        for value in values {
+            path_dependent |= value.path_dependent;
+            if attacker {
+                pn = pn.min(value.pn);
+                dn = dn.saturating_add(value.dn).min(PnEntry::Infinity);
+            } else {
+                pn = pn.saturating_add(value.pn).min(PnEntry::Infinity);
+                dn = dn.min(value.dn);
+            }
+        }
+
+        // shortest mate for the attacker, longest resistance for the defender
+        let proven = values.iter().filter(|value| value.pn == 0).map(|value| value.dist + 1);
+        let dist = if attacker { proven.min() } else { proven.max() }.unwrap_or(0);
+
+        PnEntry { pn, dn, dist, path_dependent: path_dependent && dn == 0 }
+    }
+
+    /// Index of the most proving child and of the runner up, past the end if there is none
+    fn select(values: &[PnEntry], attacker: bool) -> (usize, usize) {
+        let key = |value: &PnEntry| if attacker { value.pn } else { value.dn };
+
+        let mut best = 0;
+        let mut second = values.len();
+        for index in 1..values.len() {
+            if key(&values[index]) < key(&values[best]) {
+                second = best;
+                best = index;
+            } else if second == values.len() || key(&values[index]) < key(&values[second]) {
+                second = index;
+            }
+        }
+
+        (best, second)
+    }
+
+    /// Follows proven children, the shortest mate for the attacker and the longest defence
+    fn proof_line(&mut self, game: &mut Board) -> Vec<Move> {
+        let mut line = vec![];
+        let mut attacker = true;
+
+        while let Ok(mut children) = self.children(game, attacker) {
+            let proven = children.iter()
+                .enumerate()
+                .filter_map(|(index, (_, hash))| {
+                    self.table.get(hash).filter(|entry| entry.pn == 0).map(|entry| (index, entry.dist))
+                });
+            let next = if attacker {
+                proven.min_by_key(|(_, dist)| *dist)
+            } else {
+                proven.max_by_key(|(_, dist)| *dist)
+            };
+
+            let Some((index, _)) = next else {
+                break;
+            };
+
+            let mut mov = children.swap_remove(index).0;
+            game.mov(&mut mov);
+            line.push(mov);
+            attacker = !attacker;
+
+            if game.is_draw() {
+                break;
+            }
+        }
+
+        for mov in line.iter_mut().rev() {
+            game.unmov(mov);
+        }
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::condition::Condition::RED;
+
+    // red to move, the first row is black's back rank
+    fn position(rows: &str) -> Board {
+        let rows: Vec<&str> = rows.split("/").collect();
+        Board::from_rows(std::array::from_fn(|row| rows[row]), RED)
+    }
+
+    #[test]
+    fn proves_long_checking_mate() {
+        // the shortest checking mate takes 20 moves
+        let mut board = position("hSr....../.S..g..../........./c......H./........s/.H......./........./...RC..../R....G.../..E...E..");
+        let mut pns = ProofNumberSearch::new(ProofNumberSearch::DefaultPositions);
+        let MateResult::Mate(mut line) = pns.solve(&mut board) else {
+            panic!("no mate found in {} positions", pns.positions);
+        };
+        assert!(line.len() >= 39, "mate in {} plies is shorter than the shortest one", line.len());
+
+        for (ply, mov) in line.iter_mut().enumerate() {
+            assert!(board.get_moves(false).iter().any(|legal| legal.equals(mov)), "illegal move {} at ply {}", mov.display(), ply);
+            board.mov(mov);
+            if ply % 2 == 0 {
+                assert!(board.is_check(), "{} does not give check", mov.display());
+            }
+        }
+        assert!(board.condition() == RED, "the line does not end in mate");
+    }
+
+    #[test]
+    fn perpetual_check_is_no_mate() {
+        // red can keep checking until the position repeats, but never mate
+        let mut board = position("..e....../...g...../........./...S...../.......R./........./........./........./........./.....G...");
+        let mut pns = ProofNumberSearch::new(ProofNumberSearch::DefaultPositions);
+        assert!(matches!(pns.solve(&mut board), MateResult::NoMate));
+    }
+}
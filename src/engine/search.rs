@@ -1,50 +1,268 @@
 use std::cmp::{max, min};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use fnv::FnvHasher;
+use rand::rngs::ThreadRng;
 use crate::board::board::Board;
+use crate::board::condition::Condition;
 use crate::board::condition::Condition::{BLACK, DRAW, RED};
 use crate::board::movee::Move;
 use crate::board::piece::Piece;
+use crate::engine::eval_cache::EvalCache;
 use crate::engine::eval::Eval;
-use crate::engine::parameters::SearchParameters;
-use crate::engine::tt::TT;
-
-pub struct Engine {
+use crate::engine::eval_params::EvalParams;
+use crate::engine::evaluator::Evaluator;
+use crate::engine::history;
+use crate::engine::history::{CaptureHistory, ContinuationHistory, PieceTo};
+use crate::engine::parameters::{Heuristics, SearchConfig, SearchParameters};
+use crate::engine::skill::Skill;
+use crate::engine::stats::SearchStats;
+use crate::engine::tt::{TT, TTEntry};
+
+// mixed into tt keys when black is the root side, draw scores depend on it under contempt
+const ContemptKey: u64 = 0x6a09_e667_f3bc_c908;
+
+pub struct Engine<E: Evaluator = Eval> {
     tt: TT,
+    // static evals by position, shared by every search with this evaluator
+    eval_cache: EvalCache,
     history: Vec<Vec<Vec<i32>>>,
     killers: Vec<Vec<Move>>,
     counter: Vec<Vec<Vec<Move>>>,
 
-    pub maxpositions: i32,
+    // follow up scores for the moves one and two plies earlier
+    continuation: [ContinuationHistory; 2],
+    capture_history: CaptureHistory,
+    // piece and target square of the move made at each ply
+    move_stack: Vec<PieceTo>,
+
+    pub maxpositions: u64,
+
+    // pruning and reduction settings
+    pub config: SearchConfig,
+    pub heuristics: Heuristics,
+
+    // strength used by `play`
+    pub skill: Skill,
+
+    // static evaluation of the leaves
+    pub evaluator: E,
+
+    // counters of the last search
+    pub stats: SearchStats,
+
+    // principal variation of the last completed iteration
+    pub pv: Vec<Move>,
+
+    // side to move at the root, draws are scored for it
+    root_player: Condition,
+
+    // root move restrictions, empty means unrestricted
+    root_moves: Vec<Move>,
+    excluded_moves: Vec<Move>,
 
-    // debug
-    pub searches: i32,
+    // external search control, shared with other threads
+    stop: Arc<AtomicBool>,
+    ponder: Arc<AtomicBool>,
+    pondering: bool,
+
+    // nodes of the current search, u64 so long ponders cannot overflow it
+    pub searches: u64,
 }
 
-impl Engine {
+impl Engine<Eval> {
+    /// Handcrafted eval with the embedded default parameters
     pub fn new() -> Self {
+        Self::with_evaluator(Eval::from_params(EvalParams::defaults()))
+    }
+}
+
+impl<E: Evaluator> Engine<E> {
+    pub fn with_evaluator(evaluator: E) -> Self {
         Self {
-            tt: TT::new(),
+            tt: TT::new(SearchParameters::Size),
+            eval_cache: EvalCache::new(SearchParameters::EvalCacheSize),
             history: vec![vec![vec![0; 90]; 90]; 2],
             killers: vec![vec![Move::null(); SearchParameters::MaxKillers as usize]; SearchParameters::MaxDepth as usize],
             counter: vec![vec![vec![Move::null(); 90]; 90]; 2],
+            continuation: [ContinuationHistory::default(), ContinuationHistory::default()],
+            capture_history: CaptureHistory::default(),
+            move_stack: vec![PieceTo::default(); SearchParameters::MaxDepth as usize + 1],
             maxpositions: 0,
+            config: SearchConfig::default(),
+            heuristics: Heuristics::default(),
+            skill: Skill::default(),
+            evaluator,
+            stats: SearchStats::default(),
+            pv: vec![],
+            root_player: RED,
+            root_moves: vec![],
+            excluded_moves: vec![],
+            stop: Arc::new(AtomicBool::new(false)),
+            ponder: Arc::new(AtomicBool::new(false)),
+            pondering: false,
             searches: 0,
         }
     }
 
-    pub fn evaluate(&self, game: &mut Board) -> i32 {
+    /// Resizes the transposition table to about `megabytes`, clearing it
+    pub fn set_hash(&mut self, megabytes: usize) -> io::Result<()> {
+        self.tt.resize(megabytes)
+    }
+
+    /// Empties the transposition table and the eval cache
+    pub fn clear_hash(&mut self) {
+        self.tt.clear();
+        self.eval_cache.clear();
+    }
+
+    /// Permille of the transposition table in use
+    pub fn hashfull(&self) -> i32 {
+        self.tt.hashfull()
+    }
+
+    /// Sets an option by name: the hash size, saving or loading the hash to a file,
+    /// a heuristic switch or a search parameter
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "Hash" => {
+                let megabytes = value.parse::<usize>().map_err(|_| "invalid hash size".to_string())?;
+                if megabytes > SearchParameters::MaxSize {
+                    return Err(format!("hash size is limited to {} megabytes", SearchParameters::MaxSize));
+                }
+                self.set_hash(megabytes).map_err(|err| err.to_string())
+            }
+            "Clear Hash" => {
+                self.clear_hash();
+                Ok(())
+            }
+            "Save Hash" => self.save_tt(value).map_err(|err| format!("cannot save {}: {}", value, err)),
+            "Load Hash" => self.load_tt(value).map_err(|err| format!("cannot load {}: {}", value, err)),
+            "Skill Level" => {
+                let level = value.parse::<i32>().map_err(|_| "invalid skill level".to_string())?;
+                self.skill = Skill::new(level);
+                Ok(())
+            }
+            _ if self.heuristics.has(name) => self.heuristics.set(name, value),
+            _ => {
+                // stored scores include draws scored with the old contempt
+                let contempt = self.config.contempt;
+                self.config.set(name, value)?;
+                if self.config.contempt != contempt {
+                    self.clear_hash();
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Saves the transposition table so analysis can resume later
+    pub fn save_tt(&self, path: &str) -> io::Result<()> {
+        self.tt.save(path, Board::ZOBRIST_VERSION, self.tt_fingerprint())
+    }
+
+    /// Loads a transposition table written by `save_tt`, also restoring its size
+    pub fn load_tt(&mut self, path: &str) -> io::Result<()> {
+        self.tt.load(path, Board::ZOBRIST_VERSION, self.tt_fingerprint())
+    }
+
+    /// Identifies what stored scores depend on, the evaluator and the contempt of draw scores
+    fn tt_fingerprint(&self) -> u64 {
+        let mut hasher = FnvHasher::default();
+        self.evaluator.fingerprint().hash(&mut hasher);
+        self.config.contempt.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Flag that aborts the running search when set
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Flag that keeps the search running without a position limit while set,
+    /// clearing it (ponderhit) starts the position limit from that point
+    pub fn ponder_flag(&self) -> Arc<AtomicBool> {
+        self.ponder.clone()
+    }
+
+    /// Checks if a root move passes the search and excluded move lists
+    fn is_root_move(&self, mov: &Move) -> bool {
+        if !self.root_moves.is_empty() && !self.root_moves.iter().any(|m| m.equals(mov)) {
+            return false;
+        }
+
+        !self.excluded_moves.iter().any(|m| m.equals(mov))
+    }
+
+    /// Checks whether the search must stop
+    fn out_of_positions(&mut self) -> bool {
+        if self.stop.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        if self.pondering {
+            if self.ponder.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            // ponderhit, the position limit counts from here
+            self.pondering = false;
+            self.maxpositions = self.searches.saturating_add(self.maxpositions);
+        }
+
+        self.searches >= self.maxpositions
+    }
+
+    /// Score of a draw for the side to move, contempt counts against the root side
+    fn draw_score(&self, game: &Board) -> i32 {
+        if game.player == self.root_player {
+            -self.config.contempt
+        } else {
+            self.config.contempt
+        }
+    }
+
+    /// Transposition table key, separate for each root side once draws are not worth 0
+    fn tt_key(&self, game: &mut Board) -> u64 {
+        if self.config.contempt != 0 && self.root_player == BLACK {
+            game.get_hash() ^ ContemptKey
+        } else {
+            game.get_hash()
+        }
+    }
+
+    pub fn evaluate(&mut self, game: &mut Board) -> i32 {
         if game.is_draw() {
-            return 0;
+            return self.draw_score(game);
+        }
+
+        self.stats.evals += 1;
+        let hash = game.get_hash();
+        if let Some(score) = self.eval_cache.probe(hash) {
+            self.stats.eval_cache_hits += 1;
+            return score;
         }
 
-        let mut eval = Eval::new();
-        eval.tempo_score = 5;
-        eval.mobility_mg = [6, 0, 0, 4, 6, 2, 1];
-        eval.mobility_eg = [1, 2, 2, 11, 5, 4, 8];
+        let score = self.evaluator.evaluate(game);
+        self.eval_cache.store(hash, score);
+        score
+    }
+
+    /// Makes a move on the board and tells the evaluator
+    fn make_move(&mut self, game: &mut Board, mov: &mut Move) {
+        game.mov(mov);
+        self.evaluator.on_move(game, mov);
+    }
 
-        return eval.evaluate(game);
+    /// Takes a move back and tells the evaluator
+    fn unmake_move(&mut self, game: &mut Board, mov: &mut Move) {
+        game.unmov(mov);
+        self.evaluator.on_unmove(game, mov);
     }
 
-    fn score_moves(&self, game: &mut Board, moves: &mut Vec<Move>, ply: i32, pv_move: &Move, prev_move: &Move) {
+    fn score_moves(&self, game: &mut Board, moves: &mut Vec<Move>, ply: i32, pv_move: &Move, prev_move: &Move, earlier: &[PieceTo; 2]) {
         let ply = ply as usize;
 
         // sort by history, decreasing
@@ -58,6 +276,9 @@ impl Engine {
             } else if capture != Piece::SPACE {
                 let piece_score = game.score_piece(mov.endy as usize, mov.endx as usize);
                 score += SearchParameters::MvvLvaOffset + piece_score;
+                if self.heuristics.capture_history {
+                    score += self.capture_history.get(PieceTo::new(game, mov), capture) / SearchParameters::CaptureHistoryDivisor;
+                }
             } else if mov.equals(&self.killers[ply][0]) {
                 score += SearchParameters::MvvLvaOffset - SearchParameters::FirstKillerMoveScore;
             } else if mov.equals(&self.killers[ply][1]) {
@@ -77,6 +298,8 @@ impl Engine {
                 }
 
                 score += history_score;
+                score += self.continuation_score(earlier, PieceTo::new(game, mov));
+                score = min(score, SearchParameters::MaxHistoryScore);
             }
 
             return score;
@@ -86,6 +309,8 @@ impl Engine {
 
     pub fn qsearch(&mut self, game: &mut Board, mut alpha: i32, mut beta: i32, pv_line: &mut Vec<Move>, ply: i32, maxply: i32) -> i32 {
         self.searches += 1;
+        self.stats.nodes += 1;
+        self.stats.qnodes += 1;
 
         // conditions check that are exact
         let cond = game.condition();
@@ -94,7 +319,7 @@ impl Engine {
         } else if cond == game.player.inverse() {
             return -SearchParameters::Win + ply;
         } else if cond == DRAW {
-            return 0;
+            return self.draw_score(game);
         }
 
 
@@ -178,7 +403,7 @@ impl Engine {
         // }
         
 
-        if self.searches >= self.maxpositions {
+        if self.out_of_positions() {
             return 0;
         }
 
@@ -186,30 +411,43 @@ impl Engine {
             return self.evaluate(game);
         }
 
-        let mut best_score = self.evaluate(game);
-        let in_check = ply <= 2 && game.is_check();
+        // no standing pat when in check, every evasion is searched instead
+        let in_check = game.is_check();
+        let mut best_score = -SearchParameters::Win + ply;
+        if !in_check {
+            best_score = self.evaluate(game);
 
-        if !in_check && best_score >= beta {
-            return best_score;
-        }
+            if best_score >= beta {
+                return best_score;
+            }
 
-        if best_score > alpha {
-            alpha = best_score;
+            if best_score > alpha {
+                alpha = best_score;
+            }
         }
 
-        let mut moves = game.get_moves(!in_check);
-        self.score_moves(game, &mut moves, maxply, &Move::null(), &Move::null());
+        let mut moves = if in_check { game.get_evasions() } else { game.get_moves(true) };
+        if !in_check && ply - maxply < self.config.qsearch_check_plies {
+            self.stats.qsearch_checks += 1;
+            moves.append(&mut game.get_quiet_checks());
+        }
+        self.score_moves(game, &mut moves, maxply, &Move::null(), &Move::null(), &[PieceTo::default(); 2]);
 
         for mov in moves.iter_mut() {
             let mut child_pv_line = vec![];
 
             // todo: static exchange
 
-            game.mov(mov);
+            self.make_move(game, mov);
             let score = -self.qsearch(
                 game, -beta, -alpha, &mut child_pv_line, ply + 1, maxply,
             );
-            game.unmov(mov);
+            self.unmake_move(game, mov);
+
+            // the child was cut short, its score means nothing
+            if self.out_of_positions() {
+                return 0;
+            }
 
             if score > best_score {
                 best_score = score;
@@ -230,6 +468,55 @@ impl Engine {
         best_score
     }
 
+    /// Moves made one and two plies before `ply`, for continuation history
+    fn earlier_moves(&self, ply: i32) -> [PieceTo; 2] {
+        let ply = ply as usize;
+        let at = |back: usize| if ply >= back { self.move_stack[ply - back] } else { PieceTo::default() };
+        [at(1), at(2)]
+    }
+
+    fn continuation_score(&self, earlier: &[PieceTo; 2], mov: PieceTo) -> i32 {
+        if !self.heuristics.continuation_history {
+            return 0;
+        }
+        self.continuation[0].get(earlier[0], mov) + self.continuation[1].get(earlier[1], mov)
+    }
+
+    /// Continuation history of a quiet move or capture history of a capture
+    fn move_history(&self, earlier: &[PieceTo; 2], piece_to: PieceTo, captured: i8) -> i32 {
+        if captured == Piece::SPACE {
+            self.continuation_score(earlier, piece_to)
+        } else if self.heuristics.capture_history {
+            self.capture_history.get(piece_to, captured)
+        } else {
+            0
+        }
+    }
+
+    /// Rewards the cutoff move, and punishes the moves of the same kind searched before it
+    fn update_move_histories(&mut self, earlier: &[PieceTo; 2], best: (PieceTo, i8),
+                             quiets: &[PieceTo], captures: &[(PieceTo, i8)], depth: i32) {
+        let bonus = history::bonus(depth);
+
+        if best.1 == Piece::SPACE && self.heuristics.continuation_history {
+            for (table, earlier) in self.continuation.iter_mut().zip(earlier) {
+                table.update(*earlier, best.0, bonus);
+                for quiet in quiets {
+                    table.update(*earlier, *quiet, -bonus);
+                }
+            }
+        }
+
+        if self.heuristics.capture_history {
+            if best.1 != Piece::SPACE {
+                self.capture_history.update(best.0, best.1, bonus);
+            }
+            for (capture, victim) in captures {
+                self.capture_history.update(*capture, *victim, -bonus);
+            }
+        }
+    }
+
     fn increment_history(&mut self, game: &Board, mov: &Move, depth: i32) {
         if mov.is_quiet() {
             self.history
@@ -295,6 +582,7 @@ impl Engine {
                do_null: bool, prev_move: &Move, skip_move: &Move, is_extended: bool,
     ) -> i32 {
         self.searches += 1;
+        self.stats.nodes += 1;
 
         if ply >= SearchParameters::MaxDepth {
             return self.evaluate(game);
@@ -307,11 +595,11 @@ impl Engine {
         } else if cond == game.player.inverse() {
             return -SearchParameters::Win + ply;
         } else if cond == DRAW {
-            return 0;
+            return self.draw_score(game);
         }
 
         // fail-safe in case we fuck something up
-        if self.searches >= self.maxpositions {
+        if self.out_of_positions() {
             return 0;
         }
 
@@ -321,8 +609,9 @@ impl Engine {
         let mut can_futility_prune = false;
 
         // check extension
-        if in_check {
+        if in_check && self.heuristics.check_extension {
             depth += 1;
+            self.stats.check_extensions += 1;
         }
 
         if depth <= 0 {
@@ -330,108 +619,155 @@ impl Engine {
         }
 
         // tt probing
-        let mut tt_move = Move::null();
-        let entry = self.tt.probe(game.get_hash());
-        let (mov, tt_score, should_use) = entry.get(game.get_hash(), ply, depth, alpha, beta);
-        if mov.is_some() {
-            tt_move = mov.unwrap();
+        let entry = self.tt.probe(self.tt_key(game));
+        let tt_hit = entry.is_some();
+        self.stats.tt_probes += 1;
+        if tt_hit {
+            self.stats.tt_hits += 1;
         }
-        let tt_hit = entry.hash == game.get_hash();
-        let can_sve = entry.flag == SearchParameters::ExactFlag || entry.flag == SearchParameters::BetaFlag;  // need to be here for rust is a crybaby
-        let caniid = entry.flag == SearchParameters::BetaFlag;
+        let entry = entry.unwrap_or_default();
+        let (mut tt_move, tt_score, should_use) = entry.get(ply, depth, alpha, beta);
+        let can_sve = entry.flag() == SearchParameters::ExactFlag || entry.flag() == SearchParameters::BetaFlag;
+        let caniid = entry.flag() == SearchParameters::BetaFlag;
 
         // use tt score
         if should_use && !is_root && !skip_move.equals(&tt_move) {
+            self.stats.tt_cutoffs += 1;
             return tt_score;
         }
 
+        // static eval, computed once and shared by the pruning below, none when in check
+        let static_eval = if in_check {
+            TTEntry::NoEval
+        } else if tt_hit && entry.eval() != TTEntry::NoEval {
+            entry.eval()
+        } else {
+            self.evaluate(game)
+        };
+
         // static null move pruning
-        if !in_check && !is_pv_node && beta.abs() < SearchParameters::Checkmate {
-            let stat = self.evaluate(game);
-            let margin = (SearchParameters::StaticNullMovePruningBaseMargin * depth);
+        if self.heuristics.static_null_move && !in_check && !is_pv_node && beta.abs() < SearchParameters::Checkmate {
+            let stat = static_eval;
+            let margin = (self.config.static_null_move_margin * depth);
             if stat - margin >= beta {
+                self.stats.static_null_move_cutoffs += 1;
                 return stat - margin;
             }
         }
 
         // null move pruning
-        if do_null
+        if self.heuristics.null_move
+            && do_null
             && !in_check
             && !is_pv_node
-            && depth >= SearchParameters::NMRDepthLimit
+            && depth >= self.config.null_move_depth_limit
         // && false
         // todo: only do if has major pieces
         {
             let mut child_pv_line = vec![];
+            self.stats.null_move_tries += 1;
 
-            game.mov(&mut Move::null());
-            let R = 1 + depth / 6;
+            self.move_stack[ply as usize] = PieceTo::default();
+            self.make_move(game, &mut Move::null());
+            let R = self.config.null_move_reduction(depth);
             let score = -self.negamax(game, depth - 1 - R, ply + 1, -beta, -beta + 1, &mut child_pv_line, false, &Move::null(), &Move::null(), is_extended);
-            game.unmov(&mut Move::null());
+            self.unmake_move(game, &mut Move::null());
 
             if score >= beta && score.abs() < SearchParameters::Checkmate {
+                self.stats.null_move_cutoffs += 1;
                 return beta;
             }
         }
 
         // razoring
-        if depth <= 2 && !is_pv_node && !in_check {
-            let static_score = self.evaluate(game);
-            if (static_score + (SearchParameters::FutilityMargins[depth as usize] * 3)) < alpha {
+        if self.heuristics.razoring && depth <= self.config.razoring_depth_limit && !is_pv_node && !in_check {
+            let static_score = static_eval;
+            if (static_score + (self.config.futility_margin(depth) * self.config.razoring_margin_multiplier)) < alpha {
                 let score = self.qsearch(game, alpha, beta, &mut vec![], ply, 0);
                 if score < alpha {
+                    self.stats.razoring_cutoffs += 1;
                     return alpha;
                 }
             }
         }
 
         // futility pruning
-        if depth <= SearchParameters::FutilityPruningDepthLimit
+        if self.heuristics.futility
+            && depth <= self.config.futility_pruning_depth_limit
             && !is_pv_node
             && !in_check
             && alpha < SearchParameters::Checkmate
             && beta < SearchParameters::Checkmate {
-            let static_score = self.evaluate(game);
-            let margin = SearchParameters::FutilityMargins[depth as usize];
+            let static_score = static_eval;
+            let margin = self.config.futility_margin(depth);
             can_futility_prune = static_score + margin <= alpha;
         }
 
         // internal iterative deepening
-        if depth >= SearchParameters::IIDDepthLimit
+        if self.heuristics.internal_iterative_deepening
+            && depth >= self.config.iid_depth_limit
             && (is_pv_node || caniid)
             && tt_move.equals(&Move::null()) {
+            self.stats.iid_searches += 1;
             let mut child_pv_line = vec![];
-            self.negamax(game, depth - SearchParameters::IIDDepthReduction - 1, ply + 1, -beta, -alpha, &mut child_pv_line, true, &Move::null(), &Move::null(), is_extended);
+            self.move_stack[ply as usize] = PieceTo::default();
+            self.negamax(game, depth - self.config.iid_depth_reduction - 1, ply + 1, -beta, -alpha, &mut child_pv_line, true, &Move::null(), &Move::null(), is_extended);
             if child_pv_line.len() > 0 {
                 tt_move = child_pv_line[0].clone();
             }
         }
 
-        let mut moves = game.get_moves(false);
-        self.score_moves(game, &mut moves, ply, &tt_move, prev_move);
+        let mut moves = if in_check { game.get_evasions() } else { game.get_moves(false) };
+        if is_root {
+            moves.retain(|mov| self.is_root_move(mov));
+        }
+        let earlier = self.earlier_moves(ply);
+        self.score_moves(game, &mut moves, ply, &tt_move, prev_move, &earlier);
 
         let mut legal_moves = 0;
         let mut tt_flag = SearchParameters::AlphaFlag;
         let mut best_score = -1e9 as i32;
         let mut best_move = &Move::null();
+        let mut quiets_searched = vec![];
+        let mut captures_searched = vec![];
 
         for mov in moves.iter_mut() {
             if mov.equals(skip_move) {
                 continue;
             }
 
+            let piece_to = PieceTo::new(game, mov);
+            let move_history = self.move_history(&earlier, piece_to, mov.captured);
+
             let mut child_pv_line = vec![];
-            game.mov(mov);
+            self.make_move(game, mov);
             legal_moves += 1;
 
+            // history pruning, quiet moves that keep failing after the same earlier moves,
+            // captures are scored on capture history and can be winning, so they are never pruned here
+            if self.heuristics.history_pruning
+                && depth <= self.config.history_pruning_depth_limit
+                && !is_pv_node
+                && !in_check
+                && legal_moves > 1
+                && mov.is_quiet()
+                && move_history < -self.config.history_pruning_margin * depth
+                && !game.is_check() {
+                self.stats.history_prunes += 1;
+                self.unmake_move(game, mov);
+                continue;
+            }
+
             // late move pruning
-            if depth <= 5
+            if self.heuristics.late_move_pruning
+                && depth <= self.config.late_move_pruning_depth_limit
                 && !is_pv_node
                 && !in_check
-                && legal_moves > SearchParameters::LateMovePruningMargins[depth as usize] {
+                && legal_moves > self.config.late_move_pruning_margin(depth) {
                 let tactical = game.is_check();
                 if !tactical {
-                    game.unmov(mov);
+                    self.stats.late_move_prunes += 1;
+                    self.unmake_move(game, mov);
                     continue;
                 }
             }
@@ -441,33 +777,39 @@ impl Engine {
                 && legal_moves > 1
                 && !game.is_check()
                 && mov.is_quiet() {
-                game.unmov(mov);
+                self.stats.futility_prunes += 1;
+                self.unmake_move(game, mov);
                 continue;
             }
 
+            self.move_stack[ply as usize] = piece_to;
             let mut score;
 
             if legal_moves == 1 {
                 let mut next_depth = depth - 1;
 
                 // singular extension
-                if !is_extended
-                    && depth >= SearchParameters::SingularExtensionDepthLimit
+                if self.heuristics.singular_extension
+                    && !is_extended
+                    && depth >= self.config.singular_extension_depth_limit
                     && tt_move.equals(mov)
                     && is_pv_node
                     && tt_hit
                     && can_sve {
-                    game.unmov(mov);
+                    self.unmake_move(game, mov);
 
-                    let score_to_beat = tt_score - SearchParameters::SingularMoveMargin;
-                    let R = 1 + depth / 6;
+                    let score_to_beat = tt_score - self.config.singular_move_margin;
+                    self.move_stack[ply as usize] = PieceTo::default();
+                    let R = self.config.null_move_reduction(depth);
 
                     let next_best_score = self.negamax(game, depth - 1 - R, ply + 1, score_to_beat, score_to_beat + 1, &mut vec![], true, prev_move, mov, true);
                     if next_best_score <= score_to_beat {
-                        next_depth += SearchParameters::SingularMoveExtension;
+                        next_depth += self.config.singular_move_extension;
+                        self.stats.singular_extensions += 1;
                     }
 
-                    game.mov(mov);
+                    self.make_move(game, mov);
+                    self.move_stack[ply as usize] = piece_to;
                 }
 
                 score = -self.negamax(game, next_depth, ply + 1, -beta, -alpha, &mut child_pv_line, true, mov, &Move::null(), is_extended);
@@ -475,14 +817,19 @@ impl Engine {
                 // late move reduction
                 let tactical = in_check && !mov.is_quiet();
                 let mut reduction = 0;
-                if !is_pv_node && legal_moves >= SearchParameters::LMRLegalMovesLimit
-                    && depth >= SearchParameters::LMRDepthLimit && !tactical {
-                    reduction = SearchParameters::LMR(depth, legal_moves);
+                if self.heuristics.late_move_reduction
+                    && !is_pv_node && legal_moves >= self.config.lmr_legal_moves_limit
+                    && depth >= self.config.lmr_depth_limit && !tactical {
+                    reduction = self.config.lmr(depth, legal_moves);
+                    // reduce moves that do well after the earlier moves less
+                    reduction = max(0, reduction - move_history / self.config.lmr_history_divisor);
+                    self.stats.late_move_reductions += 1;
                 }
 
                 score = -self.negamax(game, depth - 1 - reduction, ply + 1, -(alpha + 1), -alpha, &mut child_pv_line, true, mov, &Move::null(), is_extended);
 
                 if score > alpha && reduction > 0 {
+                    self.stats.lmr_researches += 1;
                     score = -self.negamax(game, depth - 1, ply + 1, -(alpha + 1), -alpha, &mut child_pv_line, true, mov, &Move::null(), is_extended);
                     if score > alpha {
                         score = -self.negamax(game, depth - 1, ply + 1, -beta, -alpha, &mut child_pv_line, true, mov, &Move::null(), is_extended);
@@ -492,7 +839,12 @@ impl Engine {
                 }
             }
 
-            game.unmov(mov);
+            self.unmake_move(game, mov);
+
+            // an aborted child returns 0, nothing learned from it can be stored
+            if self.out_of_positions() {
+                return 0;
+            }
 
             if score > best_score {
                 best_score = score;
@@ -500,7 +852,12 @@ impl Engine {
             }
 
             if score >= beta {
+                self.stats.fail_highs += 1;
+                if legal_moves == 1 {
+                    self.stats.fail_high_first += 1;
+                }
                 tt_flag = SearchParameters::BetaFlag;
+                self.update_move_histories(&earlier, (piece_to, mov.captured), &quiets_searched, &captures_searched, depth);
                 self.increment_history(game, mov, depth);
                 self.store_killer(ply, mov);
                 self.store_counter(game, prev_move, mov);
@@ -509,6 +866,12 @@ impl Engine {
                 self.decrement_history(game, mov);
             }
 
+            if mov.is_quiet() {
+                quiets_searched.push(piece_to);
+            } else {
+                captures_searched.push((piece_to, mov.captured));
+            }
+
             if score > alpha {
                 alpha = score;
                 tt_flag = SearchParameters::ExactFlag;
@@ -522,16 +885,78 @@ impl Engine {
         }
 
         // store tt
-        let entry = self.tt.store(game.get_hash(), depth);
-        entry.set(game.get_hash(), best_score, best_move, ply, depth, tt_flag);
+        self.tt.store(self.tt_key(game), best_score, best_move, static_eval, ply, depth, tt_flag);
 
 
         best_score
     }
 
     pub fn search(&mut self, game: &mut Board, maxdepth: i32, maxpositions: i32) -> (Move, i32) {
+        self.search_moves(game, maxdepth, maxpositions, &[], &[])
+    }
+
+    /// Picks the move to play at the current skill level, the node budget is shared by all candidates
+    pub fn play(&mut self, game: &mut Board, maxdepth: i32, maxpositions: i32,
+                search_moves: &[Move], excluded: &[Move]) -> (Move, i32) {
+        if self.skill.is_full_strength() {
+            return self.search_moves(game, maxdepth, maxpositions, search_moves, excluded);
+        }
+
+        let depth = maxdepth.min(self.skill.depth());
+        let multi_pv = self.skill.multi_pv();
+        let budget = (maxpositions / multi_pv as i32).max(1);
+
+        // search the best moves one by one, excluding the ones already found
+        let mut excluded = excluded.to_vec();
+        let mut candidates: Vec<(Move, i32, Vec<Move>)> = vec![];
+        for _ in 0..multi_pv {
+            let (best_move, score) = self.search_moves(game, depth, budget, search_moves, &excluded);
+            if best_move.is_null() {
+                break;
+            }
+
+            excluded.push(best_move.clone());
+            candidates.push((best_move, score, self.pv.clone()));
+
+            if self.stop.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+
+        if candidates.is_empty() {
+            return (Move::null(), self.evaluate(game));
+        }
+
+        let scores: Vec<i32> = candidates.iter().map(|(_, score, _)| *score).collect();
+        let index = self.skill.pick(&scores, &mut ThreadRng::default());
+        let (best_move, score, pv) = candidates.swap_remove(index);
+
+        self.pv = pv;
+        (best_move, score)
+    }
+
+    /// Searches only the root moves in `search_moves` (all if empty), never the ones in `excluded`
+    pub fn search_moves(&mut self, game: &mut Board, maxdepth: i32, maxpositions: i32,
+                        search_moves: &[Move], excluded: &[Move]) -> (Move, i32) {
+        self.root_moves = search_moves.to_vec();
+        self.excluded_moves = excluded.to_vec();
+        self.root_player = game.player;
+        self.evaluator.refresh(game);
+
+        // keep only the legal moves that pass the lists, a set made of illegal moves leaves nothing
+        let legal: Vec<Move> = game.get_moves(false).into_iter().filter(|mov| self.is_root_move(mov)).collect();
+        if legal.is_empty() {
+            self.pv.clear();
+            return (Move::null(), self.evaluate(game));
+        }
+        self.root_moves = legal;
+
         self.searches = 0;
-        self.maxpositions = maxpositions;
+        self.stats = SearchStats::default();
+        self.maxpositions = maxpositions.max(0) as u64;
+        self.pondering = self.ponder.load(Ordering::Relaxed);
+        self.pv.clear();
+        self.tt.new_search();
 
 
         let mut best_move = Move::null();
@@ -540,6 +965,7 @@ impl Engine {
         let mut score = 0;
 
         let mut level = 1;
+        let mut iteration_start = 0;
         while level <= maxdepth {
             let mut pv_line = vec![];
 
@@ -547,9 +973,10 @@ impl Engine {
             score = self.negamax(game, level, 0, alpha, beta, &mut pv_line, true, &Move::null(), &Move::null(), false);
             assert_eq!(before, game.get_hash(), "checking if the hash before and after negamax is equal");
 
-            if self.searches > self.maxpositions {
-                if best_move.is_null() && level == 1 {
+            if self.out_of_positions() {
+                if best_move.is_null() && level == 1 && !pv_line.is_empty() {
                     best_move = pv_line[0].clone();
+                    self.pv = pv_line;
                 }
                 break;
             }
@@ -561,10 +988,20 @@ impl Engine {
                 continue;
             }
 
-            alpha = score - SearchParameters::Window;
-            beta = score + SearchParameters::Window;
+            alpha = score - self.config.window;
+            beta = score + self.config.window;
+
+            // no moves at the root, the game is already over
+            if pv_line.is_empty() {
+                break;
+            }
 
             best_move = pv_line[0].clone();
+            self.pv = pv_line;
+            self.stats.iteration_nodes.push(self.stats.nodes - iteration_start);
+            iteration_start = self.stats.nodes;
+            let branching = self.stats.branching_factors().last().copied().unwrap_or(0.0);
+
             let score_text = if score > SearchParameters::Checkmate {
                 format!("+M{}", SearchParameters::Win - score)
             } else if score < -SearchParameters::Checkmate {
@@ -573,16 +1010,20 @@ impl Engine {
                 format!("{}", score as f32 / 10.0)
             };
 
-            println!("Searched {}, Depth {}, PV {}, Score {}", self.searches, level, best_move.display(), score_text);
+            println!("Searched {}, Depth {}, PV {}, Score {}, Hashfull {}, BF {:.2}, FHF {:.1}%",
+                     self.searches, level, best_move.display(), score_text, self.hashfull(),
+                     branching, self.stats.fail_high_first_rate() * 100.0);
 
             // check for position limit and checkmates
-            if self.searches > maxpositions || score.abs() > SearchParameters::Checkmate - 100 {
+            if self.out_of_positions() || score.abs() > SearchParameters::Checkmate - 100 {
                 break;
             }
 
             level += 1;
         }
 
+        println!("{}", self.stats.display());
+
         (best_move, score)
     }
 }
\ No newline at end of file
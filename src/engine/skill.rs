@@ -0,0 +1,76 @@
+use rand::distr::{Bernoulli, Uniform};
+use rand::Rng;
+
+/// Playing strength, weakens play by searching shallower and picking among several candidate moves
+#[derive(Clone, Copy, Debug)]
+pub struct Skill {
+    // 0 (weakest) to MaxLevel (full strength)
+    pub level: i32,
+}
+
+impl Default for Skill {
+    fn default() -> Self {
+        Self { level: Self::MaxLevel }
+    }
+}
+
+impl Skill {
+    pub const MaxLevel: i32 = 20;
+
+    pub fn new(level: i32) -> Self {
+        Self { level: level.clamp(0, Self::MaxLevel) }
+    }
+
+    pub fn is_full_strength(&self) -> bool {
+        self.level >= Self::MaxLevel
+    }
+
+    /// Deepest iteration searched
+    pub fn depth(&self) -> i32 {
+        1 + self.level / 2
+    }
+
+    /// Number of best moves to choose from, weaker levels consider more
+    pub fn multi_pv(&self) -> usize {
+        (3 + (Self::MaxLevel - self.level) / 4) as usize
+    }
+
+    /// Score difference (in eval units) that makes a move e times less likely to be picked
+    pub fn spread(&self) -> f64 {
+        1.0 + (Self::MaxLevel - self.level) as f64
+    }
+
+    /// Chance of ignoring the scores and picking any candidate
+    pub fn blunder_chance(&self) -> f64 {
+        let weakness = (Self::MaxLevel - self.level) as f64 / Self::MaxLevel as f64;
+        0.25 * weakness * weakness
+    }
+
+    /// Index of the candidate to play, scores are from the side to move
+    pub fn pick<R: Rng>(&self, scores: &[i32], rng: &mut R) -> usize {
+        if scores.len() <= 1 {
+            return 0;
+        }
+
+        let blunder = Bernoulli::new(self.blunder_chance()).unwrap();
+        if rng.sample(blunder) {
+            return rng.sample(Uniform::new(0, scores.len()).unwrap());
+        }
+
+        // the best move has weight 1, so the weights never sum to zero
+        let best = *scores.iter().max().unwrap();
+        let weights: Vec<f64> = scores.iter()
+            .map(|score| ((*score - best) as f64 / self.spread()).exp())
+            .collect();
+
+        let mut target = rng.sample(Uniform::new(0.0, weights.iter().sum::<f64>()).unwrap());
+        for (index, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                return index;
+            }
+            target -= weight;
+        }
+
+        scores.len() - 1
+    }
+}
@@ -0,0 +1,136 @@
+/// Counters collected during one search
+#[derive(Clone, Debug, Default)]
+pub struct SearchStats {
+    // nodes, qnodes are the part of them spent in qsearch
+    pub nodes: u64,
+    pub qnodes: u64,
+
+    // transposition table
+    pub tt_probes: u64,
+    pub tt_hits: u64,
+    pub tt_cutoffs: u64,
+
+    // static evaluations asked for, and the ones answered by the eval cache
+    pub evals: u64,
+    pub eval_cache_hits: u64,
+
+    // beta cutoffs, and the ones caused by the first move searched
+    pub fail_highs: u64,
+    pub fail_high_first: u64,
+
+    pub null_move_tries: u64,
+    pub lmr_researches: u64,
+
+    // nodes spent on each completed iteration, aspiration re-searches included
+    pub iteration_nodes: Vec<u64>,
+
+    // how often each heuristic fired
+    pub null_move_cutoffs: u64,
+    pub static_null_move_cutoffs: u64,
+    pub razoring_cutoffs: u64,
+    pub futility_prunes: u64,
+    pub late_move_prunes: u64,
+    pub late_move_reductions: u64,
+    pub singular_extensions: u64,
+    pub iid_searches: u64,
+    pub check_extensions: u64,
+    pub history_prunes: u64,
+    // qsearch nodes that also searched quiet checks
+    pub qsearch_checks: u64,
+}
+
+impl SearchStats {
+    /// Fraction of beta cutoffs caused by the first move, a measure of move ordering
+    pub fn fail_high_first_rate(&self) -> f64 {
+        Self::ratio(self.fail_high_first, self.fail_highs)
+    }
+
+    pub fn tt_hit_rate(&self) -> f64 {
+        Self::ratio(self.tt_hits, self.tt_probes)
+    }
+
+    pub fn eval_cache_hit_rate(&self) -> f64 {
+        Self::ratio(self.eval_cache_hits, self.evals)
+    }
+
+    pub fn qsearch_share(&self) -> f64 {
+        Self::ratio(self.qnodes, self.nodes)
+    }
+
+    /// Null moves that failed high, out of all tried
+    pub fn null_move_success_rate(&self) -> f64 {
+        Self::ratio(self.null_move_cutoffs, self.null_move_tries)
+    }
+
+    /// Growth in nodes from the previous iteration, one per iteration after the first
+    pub fn branching_factors(&self) -> Vec<f64> {
+        self.iteration_nodes.windows(2)
+            .map(|pair| Self::ratio(pair[1], pair[0]))
+            .collect()
+    }
+
+    /// Average of `branching_factors`
+    pub fn average_branching_factor(&self) -> f64 {
+        let factors = self.branching_factors();
+        if factors.is_empty() {
+            return 0.0;
+        }
+        factors.iter().sum::<f64>() / factors.len() as f64
+    }
+
+    fn ratio(part: u64, total: u64) -> f64 {
+        if total == 0 {
+            return 0.0;
+        }
+        part as f64 / total as f64
+    }
+
+    pub fn display(&self) -> String {
+        let mut lines = vec![];
+        lines.push(format!(
+            "Nodes {}, QNodes {} ({:.1}%), QChecks {}, BF {:.2}",
+            self.nodes,
+            self.qnodes,
+            self.qsearch_share() * 100.0,
+            self.qsearch_checks,
+            self.average_branching_factor(),
+        ));
+        lines.push(format!(
+            "TT probes {}, Hits {} ({:.1}%), Cutoffs {}",
+            self.tt_probes,
+            self.tt_hits,
+            self.tt_hit_rate() * 100.0,
+            self.tt_cutoffs,
+        ));
+        lines.push(format!(
+            "Evals {}, Cache hits {} ({:.1}%)",
+            self.evals,
+            self.eval_cache_hits,
+            self.eval_cache_hit_rate() * 100.0,
+        ));
+        lines.push(format!(
+            "Fail highs {}, First move {:.1}%, Null move {}/{} ({:.1}%), LMR re-searches {}/{}",
+            self.fail_highs,
+            self.fail_high_first_rate() * 100.0,
+            self.null_move_cutoffs,
+            self.null_move_tries,
+            self.null_move_success_rate() * 100.0,
+            self.lmr_researches,
+            self.late_move_reductions,
+        ));
+        lines.push(format!(
+            "Fired: Null move {}, Static null move {}, Razoring {}, Futility {}, LMP {}, LMR {}, Singular {}, IID {}, Check ext {}, History {}",
+            self.null_move_cutoffs,
+            self.static_null_move_cutoffs,
+            self.razoring_cutoffs,
+            self.futility_prunes,
+            self.late_move_prunes,
+            self.late_move_reductions,
+            self.singular_extensions,
+            self.iid_searches,
+            self.check_extensions,
+            self.history_prunes,
+        ));
+        lines.join("\n")
+    }
+}
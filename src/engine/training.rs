@@ -8,6 +8,7 @@ use crate::board::condition::Condition::{BLACK, NONE, RED};
 use crate::board::movee::Move;
 use crate::board::piece::Piece;
 use crate::engine::eval::Eval;
+use crate::engine::eval_params::EvalParams;
 use crate::engine::search::Engine;
 
 
@@ -147,8 +148,10 @@ pub fn save_db(file: &str, db: &Vec<Game>) {
     fs::write(file, text).expect("Unable to write file");
 }
 
-pub fn find_k(db: &Vec<Game>) {
-    let mut eval = Eval::new();
+/// Fits the sigmoid scale to the game results, evaluating with the params in `params_file`
+pub fn find_k(db: &Vec<Game>, params_file: &str) {
+    let params = EvalParams::load(params_file).unwrap();
+    let mut eval = Eval::from_params(&params);
     println!("Counting positions...");
     let mut total = 0;
     for game in db.iter() {
@@ -174,6 +177,7 @@ pub fn find_k(db: &Vec<Game>) {
         let mut counts = 0;
         for game in db.iter().take(90000 / 1) {
             let mut board = Board::new();
+            board.load_params(&params);
             for mov in game.moves.iter() {
                 if counts % 10000 == 0 {
                     print!("\rCount {} / {}", counts, total);
@@ -210,8 +214,8 @@ pub fn find_k(db: &Vec<Game>) {
     }
 }
 
-pub fn tune_pst(db: &Vec<Game>) {
-    let mut eval = Eval::new();
+/// Tunes the piece tables starting from `input`, writing every improvement to `output`
+pub fn tune_pst(db: &Vec<Game>, input: &str, output: &str) {
     let k = 4.314075670609904;
     println!("Counting positions...");
     let mut total = 0;
@@ -228,13 +232,8 @@ pub fn tune_pst(db: &Vec<Game>) {
     println!("Found {} positions", total);
 
     let mut rng = rand::thread_rng();
-    let (mut mg_pst, mut eg_pst) = Eval::load_pst("./required/pst2.txt");
-    
-    let mut mobility_mg = [0,0,0,0,0,2,3];
-    let mut mobility_eg = [0,3,2,0,0,4,6];
-    let mut tempo = 5;
-    
-    let mut best_state = (mg_pst.clone(), eg_pst.clone(), mobility_mg.clone(), mobility_eg.clone(), tempo);
+    let mut params = EvalParams::load(input).unwrap();
+    let mut best_params = params.clone();
     let mut best_score = 1e9;
 
     let mut mg_rng_piece = rng.gen_range(0..7);
@@ -248,17 +247,15 @@ pub fn tune_pst(db: &Vec<Game>) {
     let mut eg_rng_dir = 0;
     
     loop {
-        eval.mobility_eg = mobility_eg.clone();
-        eval.mobility_mg = mobility_mg.clone();
-        eval.tempo_score = tempo;
+        let mut eval = Eval::from_params(&params);
         
-        println!("Best {}\nMG\n{}EG\n{}", best_score, Eval::display_pst(&best_state.0), Eval::display_pst(&best_state.1));
+        println!("Best {}\nMG\n{}EG\n{}", best_score, Eval::display_pst(&best_params.mg_pst_vec()), Eval::display_pst(&best_params.eg_pst_vec()));
         // score = 1/n sum (score_i - sigmoid(qi))^2
         let mut score = 0.0;
         let mut counts = 0;
         for game in db.iter().take(90000 / 2) {
             let mut board = Board::new();
-            board.load_pst(mg_pst.clone(), eg_pst.clone());
+            board.load_params(&params);
 
             for mov in game.moves.iter() {
                 if counts % 100000 == 0 {
@@ -284,27 +281,23 @@ pub fn tune_pst(db: &Vec<Game>) {
         println!("\nscore {}", score);
         if score < best_score {
             best_score = score;
-            best_state = (mg_pst.clone(), eg_pst.clone(), mobility_mg.clone(), mobility_eg.clone(), tempo);
-            
-            let mobmg = mobility_mg.map(|v| format!("{}", v)).join(",");
-            let mobeg = mobility_eg.map(|v| format!("{}", v)).join(",");
-            let tmp = format!("{}", tempo);
-            
-            let text = format!("{}\n{}\n{}\n{}\n{}", Eval::display_pst(&best_state.0), Eval::display_pst(&best_state.1), mobmg, mobeg, tmp);
-            fs::write("/media/terry/Games/projects/2024/mlprojects/xiangqi-rs/data/boards.txt", text).unwrap();
+            best_params = params.clone();
+            best_params.save(output).unwrap();
         } else {
-            mg_pst[mg_rng_piece][mg_rng_row][mg_rng_col] -= mg_rng_dir;
+            params.mg_pst[mg_rng_piece][mg_rng_row][mg_rng_col] -= mg_rng_dir;
             if mg_rng_col != 4 {
-                mg_pst[mg_rng_piece][mg_rng_row][8 - mg_rng_col] -= mg_rng_dir;
+                params.mg_pst[mg_rng_piece][mg_rng_row][8 - mg_rng_col] -= mg_rng_dir;
             }
-            eg_pst[eg_rng_piece][eg_rng_row][eg_rng_col] -= eg_rng_dir;
+            params.eg_pst[eg_rng_piece][eg_rng_row][eg_rng_col] -= eg_rng_dir;
             if eg_rng_col != 4 {
-                eg_pst[eg_rng_piece][eg_rng_row][8 - eg_rng_col] -= eg_rng_dir;
+                params.eg_pst[eg_rng_piece][eg_rng_row][8 - eg_rng_col] -= eg_rng_dir;
             }
             
-            mobility_mg = best_state.2.clone();
-            mobility_eg = best_state.3.clone();
-            tempo = best_state.4;
+            params.mobility_mg = best_params.mobility_mg;
+            params.mobility_eg = best_params.mobility_eg;
+            params.tempo = best_params.tempo;
+            params.king_safety = best_params.king_safety;
+            params.structure = best_params.structure;
         }
 
         // change pst
@@ -334,9 +327,9 @@ pub fn tune_pst(db: &Vec<Game>) {
         //     break;
         // }
         // 
-        // mg_pst[mg_rng_piece][mg_rng_row][mg_rng_col] += mg_rng_dir;
+        // params.mg_pst[mg_rng_piece][mg_rng_row][mg_rng_col] += mg_rng_dir;
         // if mg_rng_col != 4 {
-        //     mg_pst[mg_rng_piece][mg_rng_row][8 - mg_rng_col] += mg_rng_dir;
+        //     params.mg_pst[mg_rng_piece][mg_rng_row][8 - mg_rng_col] += mg_rng_dir;
         // }
         // 
         // 
@@ -365,24 +358,40 @@ pub fn tune_pst(db: &Vec<Game>) {
         // 
         //     break;
         // }
-        // eg_pst[eg_rng_piece][eg_rng_row][eg_rng_col] += eg_rng_dir;
+        // params.eg_pst[eg_rng_piece][eg_rng_row][eg_rng_col] += eg_rng_dir;
         // if eg_rng_col != 4 {
-        //     eg_pst[eg_rng_piece][eg_rng_row][8 - eg_rng_col] += eg_rng_dir;
+        //     params.eg_pst[eg_rng_piece][eg_rng_row][8 - eg_rng_col] += eg_rng_dir;
         // }
         
         // change tempo
         if rng.random::<f32>() < 1.0 {
-            tempo += if rng.gen_range(0..2) == 0 { -1 } else { 1 };
-            tempo = max(0, tempo);
+            params.tempo += if rng.gen_range(0..2) == 0 { -1 } else { 1 };
+            params.tempo = max(0, params.tempo);
         }
         
         // change mob score
         if rng.random::<f32>() < 1.0 {
             let options = [1,2,5,6];
             let piece = options[rng.gen_range(0..4)];
-            mobility_mg[piece] = max(0, mobility_mg[piece] + if rng.gen_range(0..2) == 0 { -1 } else { 1 });
+            params.mobility_mg[piece] = max(0, params.mobility_mg[piece] + if rng.gen_range(0..2) == 0 { -1 } else { 1 });
             let piece = options[rng.gen_range(0..4)];
-            mobility_eg[piece] = max(0, mobility_eg[piece] + if rng.gen_range(0..2) == 0 { -1 } else { 1 });
+            params.mobility_eg[piece] = max(0, params.mobility_eg[piece] + if rng.gen_range(0..2) == 0 { -1 } else { 1 });
+        }
+
+        // change a king safety weight
+        if rng.random::<f32>() < 1.0 {
+            let weights = params.king_safety.weights_mut();
+            let term = rng.gen_range(0..weights.len());
+            let phase = rng.gen_range(0..2);
+            weights[term][phase] = max(0, weights[term][phase] + if rng.gen_range(0..2) == 0 { -1 } else { 1 });
+        }
+
+        // change a structure weight, these can be negative
+        if rng.random::<f32>() < 1.0 {
+            let weights = params.structure.weights_mut();
+            let term = rng.gen_range(0..weights.len());
+            let phase = rng.gen_range(0..2);
+            weights[term][phase] += if rng.gen_range(0..2) == 0 { -1 } else { 1 };
         }
     }
 }
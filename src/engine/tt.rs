@@ -1,130 +1,349 @@
+use std::alloc::{alloc_zeroed, Layout};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
 use crate::board::movee::Move;
 use crate::engine::parameters::SearchParameters;
 
+/// A compact tt entry, all zero bits is an empty entry
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
 pub struct TTEntry {
-    pub hash: u64,
-    depth: i32,
-    score: i32,
-    best: Option<Move>,
-    pub flag: i8,
+    // lower 16 bits of the hash, the upper bits select the bucket
+    key: u16,
+    best: u16,
+    score: i16,
+    eval: i16,
+    depth: i8,
+    // generation << 2 | flag
+    gen_flag: u8,
 }
 
 impl TTEntry {
-    pub fn new() -> Self {
-        Self {
-            depth: 0,
-            hash: 0,
-            score: 0,
-            best: None,
-            flag: 0,
-        }
-    }
+    /// Marks a missing static evaluation
+    pub const NoEval: i32 = i16::MIN as i32;
 
-    pub fn get(&self, hash: u64, ply: i32, depth: i32, alpha: i32, beta: i32) -> (Option<Move>, i32, bool) {
-        let mut adjusted = 0;
+    /// Returns the best move, the mate adjusted score, and whether the score can cut off
+    pub fn get(&self, ply: i32, depth: i32, alpha: i32, beta: i32) -> (Move, i32, bool) {
+        let mut adjusted = self.score as i32;
         let mut should_use = false;
-        let mut best = &None;
-
-        if self.hash == hash {
-            best = &self.best;
-            adjusted = self.score;
 
-            if self.depth >= depth {
-                let mut score = self.score;
-                if score > SearchParameters::Checkmate {
-                    score -= ply;
-                }
+        if self.depth as i32 >= depth {
+            let mut score = self.score as i32;
+            if score > SearchParameters::Checkmate {
+                score -= ply;
+            }
 
-                if score < -SearchParameters::Checkmate {
-                    score += ply;
-                }
+            if score < -SearchParameters::Checkmate {
+                score += ply;
+            }
 
-                if self.flag == SearchParameters::ExactFlag {
-                    adjusted = score;
-                    should_use = true;
-                }
+            if self.flag() == SearchParameters::ExactFlag {
+                adjusted = score;
+                should_use = true;
+            }
 
-                if self.flag == SearchParameters::AlphaFlag && score <= alpha {
-                    adjusted = alpha;
-                    should_use = true;
-                }
+            if self.flag() == SearchParameters::AlphaFlag && score <= alpha {
+                adjusted = alpha;
+                should_use = true;
+            }
 
-                if self.flag == SearchParameters::BetaFlag && score >= beta {
-                    adjusted = beta;
-                    should_use = true;
-                }
+            if self.flag() == SearchParameters::BetaFlag && score >= beta {
+                adjusted = beta;
+                should_use = true;
             }
         }
 
-        (best.clone(), adjusted, should_use)
+        (Move::unpack(self.best), adjusted, should_use)
     }
 
-    pub fn set(&mut self, hash: u64, mut score: i32, best: &Move, ply: i32, depth: i32, flag: i8) {
-        self.hash = hash;
-        self.depth = depth;
-        self.best = Some(best.clone());
-        self.flag = flag;
-
-        if score > SearchParameters::Checkmate {
-            score += ply;
-        }
+    pub fn flag(&self) -> i8 {
+        (self.gen_flag & 3) as i8
+    }
 
-        if score < -SearchParameters::Checkmate {
-            score -= ply;
-        }
+    /// Static evaluation of the position, `NoEval` if it was not computed
+    pub fn eval(&self) -> i32 {
+        self.eval as i32
+    }
 
-        self.score = score;
+    fn generation(&self) -> u8 {
+        self.gen_flag >> 2
     }
-    
-    pub fn get_flag(&self) -> i8 {
-        self.flag
+
+    fn is_empty(&self) -> bool {
+        self.flag() == 0
     }
 }
 
+/// A cache line of entries sharing one index
+#[derive(Clone, Copy, Default)]
+#[repr(C, align(64))]
+struct Bucket {
+    entries: [TTEntry; SearchParameters::Buckets],
+}
+
+const _: () = assert!(size_of::<Bucket>() == 64, "tt buckets should fill one cache line");
+
+
+/// Saved table layout, all little endian:
+/// magic, format version (u32), zobrist version (u32), evaluator and contempt fingerprint (u64),
+/// bucket count (u64), generation (u8),
+/// then every entry as key, best, score, eval (u16/i16), depth (i8), gen_flag (u8)
+const FileMagic: &[u8; 4] = b"XQTT";
+const FileVersion: u32 = 2;
+const HeaderBytes: u64 = 29;
+const EntryBytes: usize = 10;
 
 pub struct TT {
-    size: u64,
-    entries: Vec<TTEntry>,
+    buckets: Vec<Bucket>,
+    // search counter, 6 bits, used to age out entries of old searches
+    generation: u8,
 }
 
 impl TT {
-    pub fn new() -> Self {
-        let size = SearchParameters::Size * 1024 * 1024;
-        let mut entries = vec![];
-        for _ in 0..size {
-            entries.push(TTEntry::new());
+    /// Creates a table using about `megabytes` of memory, or a single bucket if that much
+    /// cannot be allocated
+    pub fn new(megabytes: usize) -> Self {
+        let mut tt = Self {
+            buckets: vec![Bucket::default()],
+            generation: 0,
+        };
+        let _ = tt.resize(megabytes);
+        tt
+    }
+
+    /// Reallocates the table to about `megabytes` of memory, dropping all entries.
+    /// If the memory cannot be allocated the table keeps its old size, emptied
+    pub fn resize(&mut self, megabytes: usize) -> io::Result<()> {
+        let count = megabytes.max(1).checked_mul(1024 * 1024)
+            .map(|bytes| (bytes / size_of::<Bucket>()).max(1));
+        let old = self.buckets.len();
+
+        // free the old table first so both are never alive at once
+        self.buckets = vec![];
+        self.generation = 0;
+        match count.and_then(Self::allocate) {
+            Some(buckets) => {
+                self.buckets = buckets;
+                Ok(())
+            }
+            None => {
+                self.buckets = Self::allocate(old).unwrap_or_else(|| vec![Bucket::default()]);
+                Err(io::Error::new(io::ErrorKind::OutOfMemory, format!("cannot allocate {} megabytes", megabytes)))
+            }
+        }
+    }
+
+    /// Empties every entry
+    pub fn clear(&mut self) {
+        self.buckets.fill(Bucket::default());
+        self.generation = 0;
+    }
+
+    /// Size of the table in megabytes
+    pub fn megabytes(&self) -> usize {
+        self.buckets.len() * size_of::<Bucket>() / (1024 * 1024)
+    }
+
+    /// Starts a new search, older entries become preferred for replacement
+    pub fn new_search(&mut self) {
+        self.generation = (self.generation + 1) & 63;
+    }
+
+    /// Permille of the table used by the current search, sampled from the first buckets
+    pub fn hashfull(&self) -> i32 {
+        let sample = self.buckets.len().min(1000 / SearchParameters::Buckets);
+        let used = self.buckets[..sample].iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .filter(|entry| !entry.is_empty() && entry.generation() == self.generation)
+            .count();
+        (used * 1000 / (sample * SearchParameters::Buckets)) as i32
+    }
+
+    /// Writes the table to `path`, tagged with the zobrist version the hashes were made with
+    /// and a fingerprint of the evaluator and settings the stored scores came from
+    pub fn save(&self, path: &str, zobrist_version: u32, eval_fingerprint: u64) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(FileMagic)?;
+        out.write_all(&FileVersion.to_le_bytes())?;
+        out.write_all(&zobrist_version.to_le_bytes())?;
+        out.write_all(&eval_fingerprint.to_le_bytes())?;
+        out.write_all(&(self.buckets.len() as u64).to_le_bytes())?;
+        out.write_all(&[self.generation])?;
+
+        let mut bytes = [0u8; EntryBytes];
+        for entry in self.buckets.iter().flat_map(|bucket| bucket.entries.iter()) {
+            bytes[0..2].copy_from_slice(&entry.key.to_le_bytes());
+            bytes[2..4].copy_from_slice(&entry.best.to_le_bytes());
+            bytes[4..6].copy_from_slice(&entry.score.to_le_bytes());
+            bytes[6..8].copy_from_slice(&entry.eval.to_le_bytes());
+            bytes[8] = entry.depth as u8;
+            bytes[9] = entry.gen_flag;
+            out.write_all(&bytes)?;
+        }
+
+        out.flush()
+    }
+
+    /// Replaces the table with one saved by `save`, resizing to the saved size
+    pub fn load(&mut self, path: &str, zobrist_version: u32, eval_fingerprint: u64) -> io::Result<()> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != FileMagic {
+            return Err(invalid("not a tt file"));
+        }
+
+        let mut word = [0u8; 4];
+        input.read_exact(&mut word)?;
+        if u32::from_le_bytes(word) != FileVersion {
+            return Err(invalid("unsupported tt file version"));
+        }
+
+        input.read_exact(&mut word)?;
+        if u32::from_le_bytes(word) != zobrist_version {
+            return Err(invalid("tt file was saved with different zobrist keys"));
+        }
+
+        // scores and static evals are only valid for the evaluator and contempt that made them
+        let mut long = [0u8; 8];
+        input.read_exact(&mut long)?;
+        if u64::from_le_bytes(long) != eval_fingerprint {
+            return Err(invalid("tt file was saved with a different evaluator, params or contempt"));
         }
 
+        input.read_exact(&mut long)?;
+        let count = u64::from_le_bytes(long);
+        let length = std::fs::metadata(path)?.len();
+        if count == 0 || length != HeaderBytes + count * (SearchParameters::Buckets * EntryBytes) as u64 {
+            return Err(invalid("tt file size does not match its header"));
+        }
+
+        let mut generation = [0u8; 1];
+        input.read_exact(&mut generation)?;
 
-        Self {
-            size,
-            entries,
+        let mut buckets = Self::allocate(count as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "cannot allocate the saved table"))?;
+        let mut bytes = [0u8; EntryBytes];
+        for entry in buckets.iter_mut().flat_map(|bucket| bucket.entries.iter_mut()) {
+            input.read_exact(&mut bytes)?;
+            entry.key = u16::from_le_bytes([bytes[0], bytes[1]]);
+            entry.best = u16::from_le_bytes([bytes[2], bytes[3]]);
+            entry.score = i16::from_le_bytes([bytes[4], bytes[5]]);
+            entry.eval = i16::from_le_bytes([bytes[6], bytes[7]]);
+            entry.depth = bytes[8] as i8;
+            entry.gen_flag = bytes[9];
         }
+
+        self.buckets = buckets;
+        self.generation = generation[0] & 63;
+        Ok(())
     }
 
-    pub fn probe(&mut self, hash: u64) -> &mut TTEntry {
-        let index = hash % self.size;
-        if index + 1 == self.size {
-            return &mut self.entries[index as usize];
+    /// Allocates `count` empty buckets in one zeroed block, none if the size overflows or
+    /// the memory is not available
+    fn allocate(count: usize) -> Option<Vec<Bucket>> {
+        let layout = Layout::array::<Bucket>(count).ok()?;
+        if layout.size() == 0 {
+            return None;
         }
 
-        if self.entries[index as usize].hash == hash {
-            return &mut self.entries[index as usize];
+        // SAFETY: the layout is non zero sized, buckets are plain integers so zeroed memory is
+        // a valid (empty) bucket, and the vec takes ownership with the layout it was allocated with
+        unsafe {
+            let ptr = alloc_zeroed(layout) as *mut Bucket;
+            if ptr.is_null() {
+                return None;
+            }
+            Some(Vec::from_raw_parts(ptr, count, count))
         }
+    }
 
-        return &mut self.entries[(index + 1) as usize];
+    fn index(&self, hash: u64) -> (usize, u16) {
+        let index = ((hash as u128 * self.buckets.len() as u128) >> 64) as usize;
+        (index, hash as u16)
     }
 
-    pub fn store(&mut self, hash: u64, depth: i32) -> &mut TTEntry {
-        let index = hash % self.size;
-        if index + 1 == self.size {
-            return &mut self.entries[index as usize];
+    pub fn probe(&self, hash: u64) -> Option<TTEntry> {
+        let (index, key) = self.index(hash);
+        self.buckets[index].entries.iter()
+            .find(|entry| entry.key == key && !entry.is_empty())
+            .copied()
+    }
+
+    pub fn store(&mut self, hash: u64, mut score: i32, best: &Move, eval: i32, ply: i32, depth: i32, flag: i8) {
+        let (index, key) = self.index(hash);
+        let generation = self.generation;
+        let bucket = &mut self.buckets[index];
+
+        let slot = match bucket.entries.iter().position(|entry| entry.key == key && !entry.is_empty()) {
+            Some(slot) => {
+                // same position, keep a deeper result from this search unless the new one is exact
+                let old = &bucket.entries[slot];
+                if flag != SearchParameters::ExactFlag
+                    && old.generation() == generation
+                    && old.depth as i32 > depth + 3 {
+                    return;
+                }
+                slot
+            }
+            None => {
+                // replace the entry with the least depth, stale generations count as shallower
+                let value = |entry: &TTEntry| {
+                    if entry.is_empty() {
+                        return i32::MIN;
+                    }
+                    let age = (generation.wrapping_sub(entry.generation()) & 63) as i32;
+                    entry.depth as i32 - 8 * age
+                };
+
+                (0..SearchParameters::Buckets)
+                    .min_by_key(|slot| value(&bucket.entries[*slot]))
+                    .unwrap()
+            }
+        };
+
+        if score > SearchParameters::Checkmate {
+            score += ply;
         }
 
-        if self.entries[index as usize].depth <= depth {
-            return &mut self.entries[index as usize];
+        if score < -SearchParameters::Checkmate {
+            score -= ply;
         }
 
-        return &mut self.entries[(index + 1) as usize];
+        let entry = &mut bucket.entries[slot];
+
+        // keep the old move if this search did not find one
+        if !best.is_null() || entry.key != key {
+            entry.best = best.pack();
+        }
+        entry.key = key;
+        entry.score = score.clamp(-i16::MAX as i32, i16::MAX as i32) as i16;
+        // real evals stay above the sentinel so a clamped one is not read back as missing
+        entry.eval = if eval == TTEntry::NoEval {
+            i16::MIN
+        } else {
+            eval.clamp(i16::MIN as i32 + 1, i16::MAX as i32) as i16
+        };
+        entry.depth = depth.clamp(0, i8::MAX as i32) as i8;
+        entry.gen_flag = (generation << 2) | flag as u8;
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_resize_keeps_the_table() {
+        let mut tt = TT::new(1);
+        assert!(tt.resize(usize::MAX).is_err());
+        // fits the arithmetic but not the memory
+        assert!(tt.resize(1 << 40).is_err());
+        assert_eq!(tt.megabytes(), 1);
+        assert!(tt.probe(0x1234_5678_9abc_def0).is_none());
+    }
+}
@@ -3,6 +3,8 @@ use crate::board::board::Board;
 use crate::board::movee::Move;
 use crate::board::piece::Piece;
 use crate::engine::eval::Eval;
+use crate::engine::eval_params::EvalParams;
+use crate::engine::parameters::SearchParameters;
 use crate::engine::search::Engine;
 use crate::engine::training;
 use crate::engine::training::save_db;
@@ -17,8 +19,6 @@ mod server;
 fn test_pos1() {
     let moves = "B3E3,H8H4,H3HX,IXHX,H1G3,HXH5,A1A3,B8E8,I1I3,BXC8,B1C3,AXBX,G4G5,H5G5,I3H3,H4C4,G1I3,C4C1,D1E2,G5G4,E2F3,BXB2,H3HX,B2C2,C3D5";
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
     let moves: Vec<&str> = moves.split(",").collect::<Vec<&str>>();
     let moves = moves.iter().map(|st| Move::from_string(&st.to_string())).collect::<Option<Vec<Move>>>();
     let mut moves = moves.unwrap();
@@ -36,8 +36,6 @@ fn test_pos2() {
     let moves = "H3HX,B8E8,B3H3,AXA8,A1A3,BXC8,A3F3,DXE9,F3F7,H8H7,F7G7,H7H1,HXH1,IXI8,H3H6,C7C6,H6G6,EXDX,G6GX,DXD9,H1H9,E9F8,I1I3,C6C5,I3D3,C8D6,GXG9,D9DX,D3D6,E8D8,H9HX,DXD9,G9H9,A8B8,G7G9,FXE9";
     let mut board = Board::new();
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
     let moves: Vec<&str> = moves.split(",").collect::<Vec<&str>>();
     let moves = moves.iter().map(|st| Move::from_string(&st.to_string())).collect::<Option<Vec<Move>>>();
     let mut moves = moves.unwrap();
@@ -54,8 +52,6 @@ fn test_pos2() {
 fn test_pos3() {
     let moves = "H3HX,IXHX,I1I3,H8E8,H1G3,HXH2,I3I2,H2I2,G3I2,B8B4,B3E3,B4E4,D1E2,E4I4,I2G3,I4I5,B1C3,BXC8,A1B1,AXBX,B1BX,C8BX,G4G5,BXC8,G3H5,I5G5,H5G7,E8E3,C1E3,G5G3,C3E4,G3G4,G7F9,DXE9,E4D6,C8DX,F9E7,DXE8,A4A5,E9DX,D6B5,E8G7,B5A7,G7E6,E7G6,I7I6,G6H8,I6I5,H8GX,G4G8,GXI9,G8E8,I9H7,E6G7,A7B5,C7C6,H7I5,C6C5,C4C5,G7E6,I5H7,EXE9,C5C6,E6F4,E1D1,E8E4,A5A6,E4E5,A6A7,CXE8,C6C7,E8C6,H7G9,E5D5,G9F7,E9D9,B5C3,D5D4,D1D2,DXE9,C3B5,D4D5,B5C3,D5D4,C7C8,D9DX,C3B5,D4D5,B5C3,D5D4,C3B5,D4D5,C8C9,C6E8,B5C7,E9F8";
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
     let moves: Vec<&str> = moves.split(",").collect::<Vec<&str>>();
     let moves = moves.iter().map(|st| Move::from_string(&st.to_string())).collect::<Option<Vec<Move>>>();
     let mut moves = moves.unwrap();
@@ -71,8 +67,6 @@ fn test_pos3() {
 
 fn test_basic() {
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
     println!("{}", board.display());
     let mut engine = Engine::new();
     engine.search(&mut board, 15, 4000000);
@@ -82,8 +76,6 @@ fn test_pos4() {
     // level 7 leads to -m2, B8B9
     let moves = "B3BX,H8E8,H3B3,IXI8,I1I3,HXG8,I3D3,FXE9,D3D7,B8B7,D7C7,B7B1,BXB1,AXA8,B3E3,I8H8,H1I3,E8E4,F1E2,GXE8,B1B7,H8H6,A1B1,H6A6,B7B8,A8B8,B1B8,EXFX";
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
     let moves: Vec<&str> = moves.split(",").collect::<Vec<&str>>();
     let moves = moves.iter().map(|st| Move::from_string(&st.to_string())).collect::<Option<Vec<Move>>>();
     let mut moves = moves.unwrap();
@@ -100,8 +92,6 @@ fn test_pos4() {
 fn test_pos5() {
     let moves = "H3H7,G7G6,B3E3,HXG8,B1C3,BXC8,A1B1,G8F6,C4C5,IXI9,E3G3,AXBX,G4G5,G6G5,G3GX,FXE9,H7C7,E7E6,B1B6,I9IX,GXG6,C8E7,G6E6,B8G8,B6BX,G8G1,E1E2,G1I1,BXCX,H8E8,E6E8,F6E8,C7B7,E9FX,B7BX,E7F5,CXC9,DXE9,C9CX,E9DX,CXC8,EXE9,C8A8,E8C7,A8H8,F5D4,E2E1,D4C2,E1E2,I1F1,C1E3,IXGX,H8H7,C7D9,BXAX,F1F8,H7H9,F8F9,C3D5,G5F5,H1I3,C2D4,E2D2,GXG3,D2D3,D4B3,AXA9,D9B8,A9F9,B8A6,D5C3,A6C5,D3D2,C5E4,F9F7,E9EX,H9D9,E4C3,D9DX";
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
     let moves: Vec<&str> = moves.split(",").collect::<Vec<&str>>();
     let moves = moves.iter().map(|st| Move::from_string(&st.to_string())).collect::<Option<Vec<Move>>>();
     let mut moves = moves.unwrap();
@@ -117,6 +107,75 @@ fn test_pos5() {
     engine.search(&mut board, 16, 20000000);
 }
 
+/// Suite lines where the search reports a longer mate than labelled, by qsearch check plies,
+/// the labels are right, the search settles on a slower mate within the node budget
+const KnownTacticsFailures: [(i32, &[usize]); 2] = [(0, &[1, 11, 14, 19, 45, 47]), (2, &[13, 14, 42, 47])];
+
+/// Runs the mate suite without and with qsearch checks and prints the pass and fail counts
+fn tactics() {
+    // each line is the moves to the position and the mate length in moves
+    let suite = std::fs::read_to_string("./required/tactics.txt").unwrap();
+    for (plies, known_failures) in KnownTacticsFailures {
+        let mut solved = 0;
+        let mut failed = 0;
+        let mut unexpected = 0;
+        let mut nodes = 0;
+        for (index, line) in suite.lines().enumerate() {
+            let (moves, label) = line.split_once(";").unwrap();
+            let mate_in: i32 = label.parse().unwrap();
+            let mut board = Board::new();
+            for st in moves.split(",") {
+                assert!(board.try_move(&mut Move::from_string(st).unwrap()), "line {}: illegal move {}", index + 1, st);
+            }
+
+            let mut engine = Engine::new();
+            engine.config.qsearch_check_plies = plies;
+            let (_, score) = engine.search(&mut board, 30, 200000);
+
+            // the mated side is at an odd ply
+            let found = (score > SearchParameters::Checkmate).then(|| (SearchParameters::Win - score + 1) / 2);
+            if found == Some(mate_in) {
+                solved += 1;
+                nodes += engine.searches;
+                continue;
+            }
+
+            failed += 1;
+            let known = if known_failures.contains(&(index + 1)) {
+                " (known)"
+            } else {
+                unexpected += 1;
+                ""
+            };
+            match found {
+                Some(found) => println!("line {}: found mate in {}, expected mate in {}{}", index + 1, found, mate_in, known),
+                None => println!("line {}: no mate found, expected mate in {}{}", index + 1, mate_in, known),
+            }
+        }
+        println!("Checks in qsearch {}: passed {}, failed {} ({} unexpected), nodes on solved {}", plies, solved, failed, unexpected, nodes);
+    }
+}
+
+/// Prints the evaluation breakdown after a comma separated move list, with the same params the server uses
+fn trace(moves: &str, params: &EvalParams) {
+    let mut board = Board::new();
+    board.load_params(params);
+
+    for text in moves.split(",").filter(|text| !text.is_empty()) {
+        let Some(mut mov) = Move::from_string(text) else {
+            println!("cannot parse move {}", text);
+            return;
+        };
+        if !board.try_move(&mut mov) {
+            println!("illegal move {}", text);
+            return;
+        }
+    }
+
+    println!("{}", board.display());
+    print!("{}", Eval::from_params(params).evaluate_trace(&mut board).display());
+}
+
 fn start_ws() {
     serve();
 }
@@ -136,7 +195,7 @@ fn main() {
     // let mut rng = rand::thread_rng();
     // println!("{}", rng.random::<f64>());
     // let db = training::read_db("/media/terry/Games/projects/2024/mlprojects/xiangqi-rs/data/parsed.txt");
-    // training::tune_pst(&db);
+    // training::tune_pst(&db, "./required/params.json", "/media/terry/Games/projects/2024/mlprojects/xiangqi-rs/data/params.json");
     // 
     
     // let num: u32 = 12;
@@ -151,6 +210,47 @@ fn main() {
     // }
     // 
 
+    // trace [moves] [--params <file>] prints the eval breakdown instead of serving
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() >= 2 && args[1] == "trace" {
+        let params = match args.iter().position(|arg| arg == "--params") {
+            Some(index) => {
+                let Some(path) = args.get(index + 1) else {
+                    println!("--params needs a file");
+                    return;
+                };
+                match EvalParams::load(path) {
+                    Ok(params) => params,
+                    Err(err) => {
+                        println!("{}", err);
+                        return;
+                    }
+                }
+            }
+            None => EvalParams::defaults().clone(),
+        };
+        let moves = args.get(2).filter(|arg| *arg != "--params").map(String::as_str).unwrap_or("");
+        trace(moves, &params);
+        return;
+    }
+
+    // tune <db> <params in> <params out> tunes the piece tables on a parsed game database
+    if args.len() >= 2 && args[1] == "tune" {
+        if args.len() < 5 {
+            println!("usage: tune <db> <params in> <params out>");
+            return;
+        }
+        let db = training::read_db(&args[2]);
+        training::tune_pst(&db, &args[3], &args[4]);
+        return;
+    }
+
+    // tactics runs the mate suite in required/tactics.txt
+    if args.len() >= 2 && args[1] == "tactics" {
+        tactics();
+        return;
+    }
+
     start_ws();
     // test_pos1();
     // test_basic();
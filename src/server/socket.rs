@@ -1,23 +1,58 @@
 use std::env;
 use std::net::{TcpListener, TcpStream};
-use std::thread::spawn;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{spawn, JoinHandle};
 use tungstenite::{accept, WebSocket};
 use serde::{Deserialize, Serialize};
 use futures::executor::ThreadPool;
 use futures::task::SpawnExt;
 use crate::board::board::Board;
+use crate::board::condition::Condition;
 use crate::board::movee::Move;
-use crate::engine::eval::Eval;
+use crate::engine::eval::{Eval, EvalTrace};
+use crate::engine::eval_params::EvalParams;
+use crate::engine::mate::{MateResult, MateSolver};
+use crate::engine::pns::ProofNumberSearch;
+use crate::engine::evaluator::Evaluator;
+use crate::engine::nnue::{Network, Nnue};
 use crate::engine::search::Engine;
 
+// the evaluator can be swapped for a network with the EvalFile option
+type ServerEngine = Engine<Box<dyn Evaluator + Send>>;
+
 #[derive(Serialize, Deserialize)]
 struct Instruct {
-    // can be: analyze
+    // can be: analyze, ponderhit, stop, setoption, mate, trace
     method: String,
 
-    // analyze
+    // mate, the most attacker moves to look for, required unless the solver is "pns"
+    #[serde(default)]
+    depth: i32,
+    // mate, "pns" proves checking mates of any length instead
+    #[serde(default)]
+    solver: String,
+
+    // setoption
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    value: String,
+
+    // analyze, mate, trace
+    #[serde(default)]
     moves: Vec<String>,
+    #[serde(default)]
     limit: i32,
+    // keep searching the expected reply after answering
+    #[serde(default)]
+    ponder: bool,
+    // only search these root moves, all if empty
+    #[serde(default)]
+    search_moves: Vec<String>,
+    // never play these root moves
+    #[serde(default)]
+    exclude_moves: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,52 +62,274 @@ struct Response {
     
     // analyze
     best_move: String,
-    score: i32
+    score: i32,
+    // expected reply, empty if unknown
+    ponder_move: String,
+    // can be: none, red, black, draw, finished games are not searched
+    condition: String,
+}
+
+#[derive(Serialize)]
+struct TraceResponse {
+    method: String,
+    // handcrafted eval terms, even when a network is evaluating
+    trace: EvalTrace,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MateResponse {
+    method: String,
+
+    // can be: mate, nomate, unknown
+    result: String,
+    line: Vec<String>,
+}
+
+/// A search running on the expected reply while the opponent thinks
+struct Ponder {
+    handle: JoinHandle<(ServerEngine, Board, Move, i32)>,
+    stop: Arc<AtomicBool>,
+    ponder: Arc<AtomicBool>,
+}
+
+impl Ponder {
+    /// Starts pondering on the position after the best move and the expected reply
+    fn start(mut engine: ServerEngine, mut board: Board, limit: i32) -> Result<Ponder, ServerEngine> {
+        // a weakened engine searches again from scratch every move
+        if engine.pv.len() < 2 || !engine.skill.is_full_strength() {
+            return Err(engine);
+        }
+
+        let mut best_move = engine.pv[0].clone();
+        let mut reply = engine.pv[1].clone();
+        if !board.try_move(&mut best_move) || !board.try_move(&mut reply) {
+            return Err(engine);
+        }
+
+        let stop = engine.stop_flag();
+        let ponder = engine.ponder_flag();
+        stop.store(false, Ordering::Relaxed);
+        ponder.store(true, Ordering::Relaxed);
+
+        let handle = spawn(move || {
+            let (best_move, score) = engine.search(&mut board, 50, limit);
+            (engine, board, best_move, score)
+        });
+
+        Ok(Ponder { handle, stop, ponder })
+    }
+
+    /// The opponent played the expected reply, finish as a normal search
+    fn hit(self) -> (ServerEngine, Board, Move, i32) {
+        self.ponder.store(false, Ordering::Relaxed);
+        self.handle.join().unwrap()
+    }
+
+    /// The opponent played something else, drop the result but keep the engine
+    fn miss(self) -> ServerEngine {
+        self.stop.store(true, Ordering::Relaxed);
+        let (engine, _, _, _) = self.handle.join().unwrap();
+        self.stop.store(false, Ordering::Relaxed);
+        self.ponder.store(false, Ordering::Relaxed);
+        engine
+    }
+}
+
+fn send_analysis(websocket: &mut WebSocket<TcpStream>, engine: &ServerEngine, best_move: &Move, score: i32) {
+    let ponder_move = if engine.pv.len() >= 2 {
+        engine.pv[1].display()
+    } else {
+        "".to_string()
+    };
+
+    let response = Response {
+        method: "analyze".to_string(),
+        score,
+        best_move: best_move.display(),
+        ponder_move,
+        condition: Condition::NONE.display().to_lowercase(),
+    };
+
+    websocket.send(serde_json::to_string(&response).unwrap().into()).unwrap();
+}
+
+/// Reports a position that is already won, lost or drawn instead of searching it
+fn send_game_over(websocket: &mut WebSocket<TcpStream>, condition: Condition) {
+    // the condition carries the result, there is no move to score
+    let response = Response {
+        method: "analyze".to_string(),
+        score: 0,
+        best_move: "".to_string(),
+        ponder_move: "".to_string(),
+        condition: condition.display().to_lowercase(),
+    };
+
+    websocket.send(serde_json::to_string(&response).unwrap().into()).unwrap();
 }
 
-fn analyze_board(websocket: &mut WebSocket<TcpStream>, instruct: &Instruct) {
-    // parse moves
-    let moves = instruct.moves.iter().map(|s| Move::from_string(&s)).collect::<Option<Vec<Move>>>();
+/// Plays a move list from the start position
+fn load_board(websocket: &mut WebSocket<TcpStream>, params: &EvalParams, moves: &Vec<String>) -> Option<Board> {
+    let moves = moves.iter().map(|s| Move::from_string(&s)).collect::<Option<Vec<Move>>>();
     if let None = moves {
         websocket.send("failed to parse move list".into()).unwrap();
-        return;
+        return None;
     }
 
-    // execute moves
-    let mut moves = moves.unwrap();
     let mut board = Board::new();
-    let (mg_pst, eg_pst) = Eval::load_pst("./required/pst2.txt");
-    board.load_pst(mg_pst, eg_pst);
-    
-    for mov in moves.iter_mut() {
+    board.load_params(params);
+
+    for mov in moves.unwrap().iter_mut() {
         if !board.try_move(mov) {
             websocket.send("failed to execute move list".into()).unwrap();
-            return;
+            return None;
         }
     }
 
+    Some(board)
+}
+
+fn analyze_board(websocket: &mut WebSocket<TcpStream>, engine: &mut ServerEngine, params: &EvalParams, instruct: &Instruct) -> Option<Board> {
+    let search_moves = instruct.search_moves.iter().map(|s| Move::from_string(&s)).collect::<Option<Vec<Move>>>();
+    let exclude_moves = instruct.exclude_moves.iter().map(|s| Move::from_string(&s)).collect::<Option<Vec<Move>>>();
+    if search_moves.is_none() || exclude_moves.is_none() {
+        websocket.send("failed to parse root move list".into()).unwrap();
+        return None;
+    }
+
+    let mut board = load_board(websocket, params, &instruct.moves)?;
+
     println!("{}", board.display());
-    println!("{}", moves.iter().map(Move::display).collect::<Vec<String>>().join(","));
+    println!("{}", instruct.moves.join(","));
 
-    let mut engine = Engine::new();
+    let condition = board.condition();
+    if condition != Condition::NONE {
+        send_game_over(websocket, condition);
+        return None;
+    }
 
     // run analysis
-    let (best_move, score) = engine.search(&mut board, 50, instruct.limit);
-    let response = Response {
-        method: "analyze".to_string(),
-        score: score,
-        best_move: best_move.display(),
+    let (best_move, score) = engine.play(
+        &mut board, 50, instruct.limit,
+        &search_moves.unwrap(), &exclude_moves.unwrap(),
+    );
+    if best_move.is_null() {
+        websocket.send("no root moves to search".into()).unwrap();
+        return None;
+    }
+
+    send_analysis(websocket, engine, &best_move, score);
+
+    Some(board)
+}
+
+fn solve_mate(websocket: &mut WebSocket<TcpStream>, params: &EvalParams, instruct: &Instruct) {
+    // a zero depth would answer nomate without searching anything
+    if instruct.solver != "pns" && instruct.depth <= 0 {
+        websocket.send("mate needs a depth of at least 1".into()).unwrap();
+        return;
+    }
+
+    let Some(mut board) = load_board(websocket, params, &instruct.moves) else {
+        return;
     };
-    
+
+    let result = if instruct.solver == "pns" {
+        let limit = if instruct.limit > 0 { instruct.limit as i64 } else { ProofNumberSearch::DefaultPositions };
+        ProofNumberSearch::new(limit).solve(&mut board)
+    } else {
+        let limit = if instruct.limit > 0 { instruct.limit as i64 } else { MateSolver::DefaultPositions };
+        MateSolver::new(limit).solve(&mut board, instruct.depth)
+    };
+    println!("{}", result.display());
+
+    let (result, line) = match result {
+        MateResult::Mate(line) => ("mate", line.iter().map(Move::display).collect()),
+        MateResult::NoMate => ("nomate", vec![]),
+        MateResult::Unknown => ("unknown", vec![]),
+    };
+
+    let response = MateResponse {
+        method: "mate".to_string(),
+        result: result.to_string(),
+        line,
+    };
+
     websocket.send(serde_json::to_string(&response).unwrap().into()).unwrap();
 }
 
-fn handle_connection(mut websocket: WebSocket<TcpStream>) {
+fn trace_eval(websocket: &mut WebSocket<TcpStream>, params: &EvalParams, instruct: &Instruct) {
+    let Some(mut board) = load_board(websocket, params, &instruct.moves) else {
+        return;
+    };
+
+    let response = TraceResponse {
+        method: "trace".to_string(),
+        trace: Eval::from_params(params).evaluate_trace(&mut board),
+    };
+
+    websocket.send(serde_json::to_string(&response).unwrap().into()).unwrap();
+}
+
+fn set_option(websocket: &mut WebSocket<TcpStream>, engine: &mut ServerEngine, params: &mut EvalParams,
+              defaults: &EvalParams, instruct: &Instruct) {
+    if instruct.name == "ParamsFile" {
+        // an empty path goes back to the server defaults
+        if instruct.value.is_empty() {
+            *params = defaults.clone();
+        } else {
+            match EvalParams::load(&instruct.value) {
+                Ok(loaded) => *params = loaded,
+                Err(err) => {
+                    websocket.send(err.to_string().into()).unwrap();
+                    return;
+                }
+            }
+        }
+        // tables are loaded into each new board, the weights need a new evaluator
+        engine.evaluator = Box::new(Eval::from_params(params));
+        engine.clear_hash();
+        return;
+    }
+
+    if instruct.name == "EvalFile" {
+        // an empty path goes back to the handcrafted eval
+        if instruct.value.is_empty() {
+            engine.evaluator = Box::new(Eval::from_params(params));
+        } else {
+            match Network::load(&instruct.value) {
+                Ok(net) => engine.evaluator = Box::new(Nnue::new(Arc::new(net))),
+                Err(err) => {
+                    websocket.send(format!("cannot load {}: {}", instruct.value, err).into()).unwrap();
+                    return;
+                }
+            }
+        }
+        // stored evals came from the old evaluator
+        engine.clear_hash();
+        return;
+    }
+
+    if let Err(err) = engine.set_option(&instruct.name, &instruct.value) {
+        websocket.send(err.into()).unwrap();
+    }
+}
+
+fn handle_connection(mut websocket: WebSocket<TcpStream>, defaults: Arc<EvalParams>) {
+    // the engine lives as long as the connection so the tt stays warm,
+    // it is moved into the ponder thread while pondering
+    // the ParamsFile option replaces these for this connection only
+    let mut params = (*defaults).clone();
+    let mut engine = Some(ServerEngine::with_evaluator(Box::new(Eval::from_params(&params))));
+    let mut ponder: Option<Ponder> = None;
+    let mut ponder_limit = 0;
 
     loop {
         let msg = websocket.read();
         if let Err(_) = msg {
             // connection closed
+            if let Some(p) = ponder.take() {
+                p.miss();
+            }
             break;
         }
         let msg = msg.unwrap();
@@ -89,10 +346,62 @@ fn handle_connection(mut websocket: WebSocket<TcpStream>) {
             let instruct: Instruct = result.unwrap();
             match instruct.method.as_str() {
                 "analyze" => {
-                    analyze_board(&mut websocket, &instruct);
+                    // a new position means the ponder move was missed
+                    if let Some(p) = ponder.take() {
+                        engine = Some(p.miss());
+                    }
+
+                    let mut current = engine.take().unwrap();
+                    let board = analyze_board(&mut websocket, &mut current, &params, &instruct);
+
+                    match board {
+                        Some(board) if instruct.ponder => {
+                            ponder_limit = instruct.limit;
+                            match Ponder::start(current, board, ponder_limit) {
+                                Ok(p) => ponder = Some(p),
+                                Err(current) => engine = Some(current),
+                            }
+                        }
+                        _ => engine = Some(current),
+                    }
+                }
+
+                "ponderhit" => {
+                    let Some(p) = ponder.take() else {
+                        websocket.send("not pondering".into()).unwrap();
+                        continue;
+                    };
+
+                    let (current, board, best_move, score) = p.hit();
+                    send_analysis(&mut websocket, &current, &best_move, score);
 
-                    // TODO: test pondering
-                    
+                    // keep pondering on the next expected reply
+                    match Ponder::start(current, board, ponder_limit) {
+                        Ok(p) => ponder = Some(p),
+                        Err(current) => engine = Some(current),
+                    }
+                }
+
+                "stop" => {
+                    if let Some(p) = ponder.take() {
+                        engine = Some(p.miss());
+                    }
+                }
+
+                "mate" => {
+                    solve_mate(&mut websocket, &params, &instruct);
+                }
+
+                "trace" => {
+                    trace_eval(&mut websocket, &params, &instruct);
+                }
+
+                "setoption" => {
+                    if let Some(p) = ponder.take() {
+                        engine = Some(p.miss());
+                    }
+
+                    set_option(&mut websocket, engine.as_mut().unwrap(), &mut params, &defaults, &instruct);
                 }
 
                 _ => {}
@@ -102,13 +411,29 @@ fn handle_connection(mut websocket: WebSocket<TcpStream>) {
 }
 
 
+/// Arguments are an optional port and `--params <file>` to replace the embedded eval parameters
 pub fn serve() {
-    let args: Vec<String> = env::args().collect();
-    let port = if args.len() == 2  {
-        args[1].to_string()
-    } else {
-        "3030".to_string()
-    };
+    let mut args = env::args().skip(1);
+    let mut port = "3030".to_string();
+    let mut params = EvalParams::defaults().clone();
+    while let Some(arg) = args.next() {
+        if arg == "--params" {
+            let Some(path) = args.next() else {
+                println!("--params needs a file");
+                return;
+            };
+            match EvalParams::load(&path) {
+                Ok(loaded) => params = loaded,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            }
+        } else {
+            port = arg;
+        }
+    }
+    let params = Arc::new(params);
     
     println!("websocket started on port {}", port);
 
@@ -118,10 +443,11 @@ pub fn serve() {
         .create().expect("failed to create thread pool");
 
     for stream in server.incoming() {
-        pool.spawn(async {
+        let params = params.clone();
+        pool.spawn(async move {
             // handle
             let websocket = accept(stream.unwrap()).unwrap();
-            handle_connection(websocket);
+            handle_connection(websocket, params);
         }).unwrap();
     }
 }